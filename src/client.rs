@@ -1,6 +1,7 @@
-// tools/src/bin/client.rs
+// src/client.rs — `send` subcommand
 // TQUIC Client: 发送真实文件（Datagram/Stream），固定码率整形，丰富日志，CSV 可选。
-use std::fs::File;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
 use std::hash::{Hash, Hasher};
 use std::io::{self, Write};
 use std::net::SocketAddr;
@@ -8,12 +9,12 @@ use std::path::PathBuf;
 use std::rc::Rc;
 use std::time::{Duration, Instant};
 
-use bytes::{BufMut, Bytes, BytesMut};
-use clap::Parser;
-use log::{debug, error, info};
-use mio::event::Event;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use clap::Args;
+use log::{debug, error, info, warn};
 use tquic::{Config, Connection, Endpoint, Error, PacketInfo, TlsConfig, TransportHandler, CongestionControlAlgorithm};
-use qskt::{QuicSocket, Result};
+
+use crate::qskt::{QuicSocket, Result};
 
 #[cfg(unix)]
 use std::os::unix::fs::FileExt;
@@ -53,35 +54,96 @@ impl std::str::FromStr for Mode {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FecMode {
+    Xor,
+    Rs,
+}
+impl std::str::FromStr for FecMode {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "xor" => Ok(FecMode::Xor),
+            "rs" => Ok(FecMode::Rs),
+            _ => Err(format!("invalid fec mode: {s}")),
+        }
+    }
+}
+
+/// Which driver runs the `send` transfer: the default `mio` readiness
+/// loop (`client::run`), or the completion-based `async` driver in
+/// `runtime_async` (requires the `async-runtime` feature).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RuntimeMode {
+    Mio,
+    Async,
+}
+impl std::str::FromStr for RuntimeMode {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "mio" => Ok(RuntimeMode::Mio),
+            "async" => Ok(RuntimeMode::Async),
+            _ => Err(format!("invalid runtime: {s}")),
+        }
+    }
+}
+
 // 与服务端相同的 Datagram 头
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 struct DgHdr {
     file_id: u64,
     total_size: u64,
+    // Real file offset for data shards; the base offset of shard 0 of the
+    // coding block for PARITY shards (so the receiver can place a
+    // recovered shard without ever seeing it directly).
     offset: u64,
+    // Real payload length for data shards; the (zero-padded) shard length
+    // of the coding block for PARITY shards.
     len: u32,
     flags: u8,
-    _pad: [u8; 3],
+    // k for this datagram's FEC coding block, 0 if FEC is disabled.
+    fec_k: u8,
+    // Shard index within the block for data shards; parity row index
+    // (see fec::rs_parity) for PARITY shards.
+    parity_idx: u8,
+    _pad: u8,
+    // FEC coding block id, monotonically increasing per block.
+    group_id: u32,
     send_ts_ns: u64,
 }
+const DG_FLAG_LAST: u8 = 0x01;
+const DG_FLAG_RETRANSMIT: u8 = 0x02;
+// Set on parity shards (never on data shards) so the receiver knows
+// `offset`/`len`/`parity_idx` are block coordinates, not file coordinates.
+const DG_FLAG_PARITY: u8 = 0x04;
+// Set on parity shards produced in RS mode (r can be > 1); absent means
+// the single-parity XOR special case. Purely informational — decode
+// doesn't need to distinguish the two, since XOR is RS with r=1.
+const DG_FLAG_FEC_RS: u8 = 0x08;
+
+// Must match the server's CTRL_STREAM_ID: the reliable bidi stream the
+// server opens to carry NACKs for dropped datagram ranges.
+const CTRL_STREAM_ID: u64 = 5;
+
 impl DgHdr {
-    const SIZE: usize = 40;
+    const SIZE: usize = 44;
     fn write_to(&self, b: &mut BytesMut) {
         b.put_u64_le(self.file_id);
         b.put_u64_le(self.total_size);
         b.put_u64_le(self.offset);
         b.put_u32_le(self.len);
         b.put_u8(self.flags);
+        b.put_u8(self.fec_k);
+        b.put_u8(self.parity_idx);
         b.put_u8(0);
-        b.put_u8(0);
-        b.put_u8(0);
+        b.put_u32_le(self.group_id);
         b.put_u64_le(self.send_ts_ns);
     }
 }
 
-#[derive(Parser, Debug, Clone)]
-#[clap(name = "client")]
+#[derive(Args, Debug, Clone)]
 pub struct ClientOpt {
     /// Log level
     #[clap(long, default_value = "INFO")]
@@ -103,6 +165,13 @@ pub struct ClientOpt {
     #[clap(long)]
     pub qlog_file: Option<String>,
 
+    /// Resume from --session-file (if present) and send the first file
+    /// chunks as 0-RTT early data instead of waiting for the handshake to
+    /// complete. The session is (re)written to --session-file whenever the
+    /// server issues a new one. Datagram mode only.
+    #[clap(long)]
+    pub enable_0rtt: bool,
+
     /// DATAGRAM local config
     #[clap(long, default_value = "65535")]
     pub max_datagram_frame_size: usize,
@@ -125,10 +194,17 @@ pub struct ClientOpt {
     #[clap(long, default_value = "1200")]
     pub chunk_bytes: usize,
 
-    /// Rate shaping (Mbps)
+    /// Rate shaping (Mbps). Acts as a hard cap on the adaptive pacer below
+    /// rather than a fixed rate: sending never goes faster than this, but
+    /// may go slower while the delivery-rate estimate ramps up.
     #[clap(long, default_value = "10.0")]
     pub rate_mbps: f64,
 
+    /// Bytes-in-flight budget, as a multiple of filtered_rate * smoothed
+    /// RTT, before the adaptive pacer holds off sending more.
+    #[clap(long, default_value = "2.0")]
+    pub cwnd_gain: f64,
+
     /// CSV for send logs (optional)
     #[clap(long)]
     pub csv_send: Option<String>,
@@ -136,20 +212,76 @@ pub struct ClientOpt {
     /// CCA name (optional)
     #[clap(long)]
     pub cca: Option<String>,
+
+    /// Track in-flight datagram chunks and react to receiver NACKs / local
+    /// retransmit timeouts instead of firing datagrams once and forgetting
+    /// them. Only meaningful in Datagram mode.
+    #[clap(long)]
+    pub reliable: bool,
+
+    /// How long an in-flight, un-acknowledged chunk is given before the
+    /// client proactively retransmits it (used when --reliable is set).
+    #[clap(long, default_value = "100")]
+    pub nack_interval_ms: u64,
+
+    /// Group every N data chunks into an FEC coding block and emit
+    /// parity datagram(s) so the receiver can reconstruct lost members
+    /// without waiting a round trip for a retransmit. 0 disables FEC.
+    /// Only meaningful in Datagram mode.
+    #[clap(long, default_value = "0")]
+    pub fec_k: u8,
+
+    /// Parity shards per coding block. Forced to 1 when --fec-mode=xor,
+    /// since XOR can only recover a single lost shard per block.
+    #[clap(long, default_value = "1")]
+    pub fec_r: u8,
+
+    /// xor|rs
+    #[clap(long, default_value = "xor")]
+    pub fec_mode: FecMode,
+
+    /// mio|async. The async driver (see `runtime_async`) needs the crate's
+    /// `async-runtime` feature; without it, --runtime async fails fast
+    /// with an explanatory error instead of silently falling back to mio.
+    /// Note: today `async` shares a reactor thread instead of owning one,
+    /// but does not yet do true completion-based zero-copy/batched I/O or
+    /// reduce backpressure versus `mio` — see `runtime_async`'s module docs.
+    #[clap(long, default_value = "mio")]
+    pub runtime: RuntimeMode,
+
+    /// Test hook for connection migration: once this many bytes have been
+    /// sent, open a second local socket, probe it as a new path, and
+    /// migrate the connection's active path to it once validated —
+    /// continuing the transfer from the current offset rather than
+    /// restarting. Unset disables migration. Only supported by the
+    /// default `--runtime mio` driver.
+    #[clap(long)]
+    pub migrate_after: Option<u64>,
 }
 
 const MAX_BUF_SIZE: usize = 64 * 1024;
 
-struct Client {
-    endpoint: Endpoint,
-    poll: mio::Poll,
-    sock: Rc<QuicSocket>,
+// Shared by both drivers (`run` below and `runtime_async`): owns the
+// endpoint/socket/poll machinery that `ClientHandler` pushes bytes
+// through. `pub(crate)` purely so `runtime_async` can drive the same
+// connect/process_connections/on_timeout sequence without duplicating
+// `Config` setup.
+pub(crate) struct Client {
+    pub(crate) endpoint: Endpoint,
+    pub(crate) poll: mio::Poll,
+    pub(crate) sock: Rc<QuicSocket>,
     context: Rc<std::cell::RefCell<ClientContext>>,
     recv_buf: Vec<u8>,
+
+    // --migrate-after: the second socket opened once ClientHandler asks
+    // for one (see ClientContext::migration_requested), kept alive here
+    // so it isn't dropped once registered, and polled alongside `sock`.
+    ipv4: bool,
+    probe_sock: Option<Rc<QuicSocket>>,
 }
 
 impl Client {
-    fn new(opt: &ClientOpt) -> Result<Self> {
+    pub(crate) fn new(opt: &ClientOpt) -> Result<Self> {
         let mut cfg = Config::new()?;
         cfg.set_max_idle_timeout(opt.idle_timeout);
         if let Some(cca) = &opt.cca {
@@ -177,7 +309,11 @@ impl Client {
             opt.datagram_event_mask,
         );
 
-        let ctx = Rc::new(std::cell::RefCell::new(ClientContext { finish: false }));
+        let ctx = Rc::new(std::cell::RefCell::new(ClientContext {
+            finish: false,
+            migration_requested: false,
+            migration_local_addr: None,
+        }));
         let handler = ClientHandler::new(opt, ctx.clone());
 
         let poll = mio::Poll::new()?;
@@ -193,16 +329,26 @@ impl Client {
             sock,
             context: ctx,
             recv_buf: vec![0u8; MAX_BUF_SIZE],
+            ipv4: opt.connect_to.is_ipv4(),
+            probe_sock: None,
         })
     }
 
-    fn finish(&self) -> bool {
+    pub(crate) fn finish(&self) -> bool {
         self.context.borrow().finish
     }
 
-    fn process_read_event(&mut self, ev: &Event) -> Result<()> {
+    // Takes a bare `mio::Token` rather than `&Event` since that's all this
+    // needs; `runtime_async` has no mio `Event` to hand in (its wakeups
+    // come from the compio reactor instead), only the token of the one
+    // socket `Client::new` registers.
+    pub(crate) fn process_read_event(&mut self, token: mio::Token) -> Result<()> {
+        let sock = match &self.probe_sock {
+            Some(probe) if probe.token() == token => probe.clone(),
+            _ => self.sock.clone(),
+        };
         loop {
-            let (len, local, remote) = match self.sock.recv_from(&mut self.recv_buf, ev.token()) {
+            let (len, local, remote) = match sock.recv_from(&mut self.recv_buf, token) {
                 Ok(v) => v,
                 Err(e) => {
                     if e.kind() == std::io::ErrorKind::WouldBlock {
@@ -222,10 +368,38 @@ impl Client {
         }
         Ok(())
     }
+
+    // --migrate-after: once ClientHandler has flagged that the transfer
+    // crossed the byte threshold, open and register a second local
+    // socket and hand its address back so the handler can probe it as a
+    // new path on the next send opportunity. A no-op once the probe
+    // socket already exists.
+    pub(crate) fn service_migration_request(&mut self) -> Result<()> {
+        if self.probe_sock.is_some() {
+            return Ok(());
+        }
+        if !self.context.borrow().migration_requested {
+            return Ok(());
+        }
+        let sock = Rc::new(QuicSocket::new_client_socket(
+            self.ipv4,
+            self.poll.registry(),
+        )?);
+        info!("migration: opened probe socket at {}", sock.local_addr());
+        self.context.borrow_mut().migration_local_addr = Some(sock.local_addr());
+        self.probe_sock = Some(sock);
+        Ok(())
+    }
 }
 
 struct ClientContext {
     finish: bool,
+    // --migrate-after round-trip with the run loop: the handler sets
+    // this once the byte threshold is crossed, asking the loop to open
+    // a second socket (see `Client::service_migration_request`); the loop
+    // answers via `migration_local_addr` once it's done so.
+    migration_requested: bool,
+    migration_local_addr: Option<SocketAddr>,
 }
 
 struct ClientHandler {
@@ -241,19 +415,80 @@ struct ClientHandler {
     sent_bytes: u64,
     file_id: u64,
 
-    // pacing
+    // pacing: interval_per_chunk starts out derived from --rate-mbps and is
+    // then continuously re-derived from the adaptive delivery-rate
+    // estimate below (see on_chunk_acked), with --rate-mbps kept as a cap.
     interval_per_chunk: Duration,
     next_deadline: Instant,
 
+    // adaptive delivery-rate pacing (BBR-style bandwidth sampling)
+    acked_bytes: u64,
+    last_ack_time: Option<Instant>,
+    last_acked_bytes: u64,
+    srtt: Duration,
+    // windowed max-filter over ~1 RTT of rate samples
+    rate_samples: VecDeque<(Instant, f64)>,
+    filtered_rate: f64,
+    cwnd_gain: f64,
+    // (sent_at, len) for datagram chunks sent but not yet acked; also used
+    // to compute bytes-in-flight for the cwnd gate
+    inflight_chunks: VecDeque<(Instant, u32)>,
+
     // stream
     stream_id: Option<u64>,
 
+    // reassembly buffer for NACK control messages arriving on CTRL_STREAM_ID
+    ctrl_recv_buf: Vec<u8>,
+
+    // reliable-datagram ARQ (see --reliable)
+    reliable: bool,
+    nack_interval: Duration,
+    // offset -> (sent_ts_ns, payload) for chunks not yet known to be delivered
+    inflight: HashMap<u64, (u64, Bytes)>,
+    // ranges the receiver has NACKed, or our own retransmit timeouts, queued
+    // ahead of fresh data
+    retransmit_queue: VecDeque<(u64, u32)>,
+    // highest offset below which the receiver has reported full contiguous
+    // coverage; entries below it are dropped from `inflight`
+    contiguous_cursor: u64,
+
+    // forward error correction (see --fec-k/--fec-r/--fec-mode)
+    fec_k: u8,
+    fec_r: u8,
+    fec_mode: FecMode,
+    fec_group_id: u32,
+    // data shards buffered for the in-progress coding block: (offset, payload)
+    fec_block: Vec<(u64, Vec<u8>)>,
+
+    // shared with the run loop so it knows when to stop polling
+    ctx: Rc<std::cell::RefCell<ClientContext>>,
+
     // logs
     csv: Option<File>,
+
+    // 0-RTT / session resumption (see --enable-0rtt)
+    session_file: Option<PathBuf>,
+    // loaded from --session-file at startup; consumed by the first
+    // on_conn_created
+    resume_session: Option<Vec<u8>>,
+    // set while early data sent in on_conn_created hasn't yet been
+    // confirmed accepted or rejected by on_conn_established
+    early_data_pending: bool,
+
+    keylog: Option<File>,
+    qlog: Option<File>,
+
+    // connection migration (see --migrate-after)
+    connect_to: SocketAddr,
+    migrate_after: Option<u64>,
+    migrated: bool,
+    // Some once a probe on the new path has been started; None before
+    // that and while waiting for the run loop to open the probe socket.
+    migration_path_id: Option<u64>,
 }
 
 impl ClientHandler {
-    fn new(opt: &ClientOpt, _ctx: Rc<std::cell::RefCell<ClientContext>>) -> Self {
+    fn new(opt: &ClientOpt, ctx: Rc<std::cell::RefCell<ClientContext>>) -> Self {
         let file = File::open(&opt.in_file).expect("open input file");
         let total_size = file.metadata().unwrap().len();
         let bytes_per_sec = (opt.rate_mbps * 1e6 / 8.0) as usize;
@@ -281,6 +516,33 @@ impl ClientHandler {
             None => None,
         };
 
+        let keylog = match &opt.keylog_file {
+            Some(p) => Some(OpenOptions::new().create(true).append(true).open(p).unwrap()),
+            None => None,
+        };
+        let qlog = match &opt.qlog_file {
+            Some(p) => Some(OpenOptions::new().create(true).append(true).open(p).unwrap()),
+            None => None,
+        };
+
+        let session_file = opt.session_file.as_ref().map(PathBuf::from);
+        let resume_session = if opt.enable_0rtt && opt.mode == Mode::Datagram {
+            session_file.as_ref().and_then(|p| std::fs::read(p).ok())
+        } else {
+            if opt.enable_0rtt {
+                warn!("--enable-0rtt is only supported in Datagram mode, ignoring");
+            }
+            None
+        };
+
+        let fec_k = if opt.fec_k > 0 && opt.mode != Mode::Datagram {
+            warn!("--fec-k is only meaningful in Datagram mode, ignoring");
+            0
+        } else {
+            opt.fec_k
+        };
+        let fec_r = if opt.fec_mode == FecMode::Xor { 1 } else { opt.fec_r.max(1) };
+
         Self {
             mode: opt.mode,
             in_file: opt.in_file.clone(),
@@ -292,11 +554,197 @@ impl ClientHandler {
             file_id,
             interval_per_chunk: interval,
             next_deadline: Instant::now(),
+            acked_bytes: 0,
+            last_ack_time: None,
+            last_acked_bytes: 0,
+            srtt: Duration::from_millis(100),
+            rate_samples: VecDeque::new(),
+            filtered_rate: bytes_per_sec as f64,
+            cwnd_gain: opt.cwnd_gain,
+            inflight_chunks: VecDeque::new(),
             stream_id: None,
+            ctrl_recv_buf: Vec::new(),
+            reliable: opt.reliable,
+            nack_interval: Duration::from_millis(opt.nack_interval_ms),
+            inflight: HashMap::new(),
+            retransmit_queue: VecDeque::new(),
+            contiguous_cursor: 0,
+            fec_k,
+            fec_r,
+            fec_mode: opt.fec_mode,
+            fec_group_id: 0,
+            fec_block: Vec::new(),
+            ctx,
             csv,
+            session_file,
+            resume_session,
+            early_data_pending: false,
+            keylog,
+            qlog,
+            connect_to: opt.connect_to,
+            migrate_after: opt.migrate_after,
+            migrated: false,
+            migration_path_id: None,
+        }
+    }
+
+    // Emit an application-level qlog event as a JSON Text Sequence record
+    // (RFC 7464), matching the server's event stream so both ends can be
+    // loaded side by side in a qvis-style viewer. Flushed on every call so
+    // a crashed run still yields a loadable trace.
+    fn qlog_event(&mut self, name: &str, data: &str) {
+        if let Some(q) = &mut self.qlog {
+            let now_ns = monotonic_ns();
+            let _ = writeln!(
+                q,
+                "\u{1e}{{\"time\":{now_ns},\"name\":\"{name}\",\"data\":{data}}}"
+            );
+            let _ = q.flush();
+        }
+    }
+
+    fn fec_enabled(&self) -> bool {
+        self.fec_k > 0
+    }
+
+    fn bytes_in_flight(&self) -> u64 {
+        self.inflight_chunks.iter().map(|&(_, len)| len as u64).sum()
+    }
+
+    // Opportunistically snapshot the TLS session (available once the
+    // server's session ticket arrives) so the next run of this client can
+    // resume with --enable-0rtt instead of paying a full handshake.
+    fn maybe_persist_session(&mut self, conn: &mut Connection) {
+        let Some(path) = &self.session_file else {
+            return;
+        };
+        let Some(session) = conn.session() else {
+            return;
+        };
+        match std::fs::write(path, &session) {
+            Ok(()) => debug!(
+                "persisted {} bytes of session data to {}",
+                session.len(),
+                path.display()
+            ),
+            Err(e) => warn!("failed to persist session to {}: {e:?}", path.display()),
+        }
+    }
+
+    // --migrate-after test hook: once `sent_bytes` crosses the threshold,
+    // ask the run loop (via `ctx`) for a second local socket, probe it as
+    // a new path with PATH_CHALLENGE/PATH_RESPONSE, and once tquic
+    // reports it validated, migrate the connection's active path to it.
+    // The transfer keeps going from the current `sent_bytes` offset the
+    // whole time — nothing here touches file position or in-flight state.
+    fn maybe_migrate(&mut self, conn: &mut Connection) {
+        let Some(threshold) = self.migrate_after else {
+            return;
+        };
+        if self.migrated || self.sent_bytes < threshold {
+            return;
+        }
+
+        let Some(path_id) = self.migration_path_id else {
+            let local_addr = {
+                let mut ctx = self.ctx.borrow_mut();
+                ctx.migration_requested = true;
+                ctx.migration_local_addr.take()
+            };
+            let Some(local_addr) = local_addr else {
+                // Run loop hasn't opened the probe socket yet; retry on
+                // the next send opportunity.
+                return;
+            };
+            match conn.probe_path(local_addr, self.connect_to) {
+                Ok(path_id) => {
+                    info!(
+                        "{} probing new path {local_addr} -> {} (path_id={path_id})",
+                        conn.trace_id(),
+                        self.connect_to
+                    );
+                    self.migration_path_id = Some(path_id);
+                }
+                Err(e) => warn!("{} failed to probe new path {local_addr}: {e:?}", conn.trace_id()),
+            }
+            return;
+        };
+
+        if conn.is_path_validated(path_id) {
+            let trace_id = conn.trace_id().to_string();
+            match conn.migrate_path(path_id) {
+                Ok(()) => {
+                    info!(
+                        "{trace_id} migrated active path to path_id={path_id} at {} bytes sent",
+                        self.sent_bytes
+                    );
+                    self.migrated = true;
+                }
+                Err(e) => warn!("{trace_id} failed to migrate to validated path_id={path_id}: {e:?}"),
+            }
+        }
+    }
+
+    // Treat on_datagram_acked as acking the oldest still-in-flight chunk —
+    // the callback itself carries no identifying info, but acks arrive
+    // close enough to send order for this FIFO approximation to track the
+    // path well. Updates the smoothed RTT, the windowed-max delivery-rate
+    // filter, and the pacing interval derived from it.
+    fn on_chunk_acked(&mut self) {
+        let now = Instant::now();
+        let Some((sent_at, len)) = self.inflight_chunks.pop_front() else {
+            return;
+        };
+
+        let rtt_sample = now.saturating_duration_since(sent_at);
+        self.srtt = (self.srtt * 7 + rtt_sample) / 8;
+
+        self.acked_bytes += len as u64;
+        if let Some(last) = self.last_ack_time {
+            let elapsed = now.saturating_duration_since(last).as_secs_f64();
+            if elapsed > 0.0 {
+                let delta_bytes = (self.acked_bytes - self.last_acked_bytes) as f64;
+                self.rate_samples.push_back((now, delta_bytes / elapsed));
+            }
+        }
+        self.last_ack_time = Some(now);
+        self.last_acked_bytes = self.acked_bytes;
+
+        while let Some(&(ts, _)) = self.rate_samples.front() {
+            if now.saturating_duration_since(ts) > self.srtt {
+                self.rate_samples.pop_front();
+            } else {
+                break;
+            }
+        }
+        let measured = self.rate_samples.iter().fold(0.0f64, |m, &(_, r)| m.max(r));
+        if measured > 0.0 {
+            // --rate-mbps remains a hard cap on top of the estimate.
+            self.filtered_rate = measured.min(self.bytes_per_sec as f64);
+            self.interval_per_chunk = Duration::from_secs_f64(self.chunk as f64 / self.filtered_rate);
+            self.qlog_event(
+                "recovery",
+                &format!(
+                    "{{\"sent_bytes\":{},\"filtered_rate_bytes_per_sec\":{:.2},\"interval_per_chunk_us\":{}}}",
+                    self.sent_bytes,
+                    self.filtered_rate,
+                    self.interval_per_chunk.as_micros()
+                ),
+            );
         }
     }
 
+    // Same FIFO approximation as on_chunk_acked: on_datagram_drop/_losted
+    // carry no identifying info either, so treat the loss as applying to
+    // the oldest still-in-flight chunk. Unlike an ack this shouldn't feed
+    // the RTT/rate filters (a lost datagram says nothing about path
+    // latency or goodput), but it still has to free up cwnd budget —
+    // otherwise bytes_in_flight() only ever grows under sustained loss and
+    // the gate in try_send_more blocks forever.
+    fn on_chunk_lost(&mut self) {
+        self.inflight_chunks.pop_front();
+    }
+
     fn log_send(&mut self, now_ns: u64, off: u64, sz: usize, mode: &str) {
         if let Some(f) = &mut self.csv {
             let _ = writeln!(
@@ -307,13 +755,204 @@ impl ClientHandler {
         }
     }
 
+    // Move any in-flight chunk whose retransmit timeout has elapsed onto the
+    // retransmit queue, so it gets re-sent even if the receiver's own NACK
+    // was itself lost.
+    fn requeue_stale_inflight(&mut self) {
+        if !self.reliable {
+            return;
+        }
+        let now_ns = monotonic_ns();
+        let timeout_ns = self.nack_interval.as_nanos() as u64;
+        let queued: Vec<u64> = self.retransmit_queue.iter().map(|(off, _)| *off).collect();
+        let mut stale = Vec::new();
+        for (&off, (sent_ts_ns, payload)) in self.inflight.iter() {
+            if queued.contains(&off) {
+                continue;
+            }
+            if now_ns.saturating_sub(*sent_ts_ns) >= timeout_ns {
+                stale.push((off, payload.len() as u32));
+            }
+        }
+        for entry in stale {
+            self.retransmit_queue.push_back(entry);
+        }
+    }
+
+    fn send_datagram_chunk(
+        &mut self,
+        conn: &mut Connection,
+        off: u64,
+        buf: &[u8],
+        retransmit: bool,
+        fec_stamp: Option<(u32, u8, u8)>,
+    ) -> bool {
+        let payload_size = buf.len();
+        let mut flags = if retransmit { DG_FLAG_RETRANSMIT } else { 0 };
+        if off + payload_size as u64 >= self.total_size {
+            flags |= DG_FLAG_LAST;
+        }
+        let (group_id, fec_k, parity_idx) = fec_stamp.unwrap_or((0, 0, 0));
+        let hdr = DgHdr {
+            file_id: self.file_id,
+            total_size: self.total_size,
+            offset: off,
+            len: payload_size as u32,
+            flags,
+            fec_k,
+            parity_idx,
+            _pad: 0,
+            group_id,
+            send_ts_ns: unix_epoch_ns(),
+        };
+        let mut packet = BytesMut::with_capacity(DgHdr::SIZE + payload_size);
+        hdr.write_to(&mut packet);
+        packet.extend_from_slice(buf);
+        let length = packet.len();
+        match conn.datagram_send(packet.freeze(), Some(length), false) {
+            Ok(()) | Err(Error::Done) => {
+                debug!(
+                    "[DGRAM] send offset={} len={} total_size={} send_ts_ns={} retransmit={retransmit}",
+                    hdr.offset, hdr.len, hdr.total_size, hdr.send_ts_ns,
+                );
+                self.log_send(
+                    hdr.send_ts_ns,
+                    off,
+                    payload_size,
+                    if retransmit { "retransmit" } else { "datagram" },
+                );
+                self.qlog_event(
+                    "datagram_sent",
+                    &format!(
+                        "{{\"file_id\":\"{:016x}\",\"offset\":{},\"len\":{},\"flags\":{}}}",
+                        hdr.file_id, hdr.offset, hdr.len, hdr.flags
+                    ),
+                );
+                if self.reliable {
+                    // Deliberately `monotonic_ns()`, not `hdr.send_ts_ns`:
+                    // this timestamp only ever gets compared against this
+                    // same process's own `monotonic_ns()` clock in
+                    // `requeue_stale_inflight`, whereas `hdr.send_ts_ns` is
+                    // wall-clock time meant for the server's cross-process
+                    // latency computation.
+                    self.inflight
+                        .insert(off, (monotonic_ns(), Bytes::copy_from_slice(buf)));
+                }
+                self.inflight_chunks.push_back((Instant::now(), payload_size as u32));
+                true
+            }
+            Err(e) => {
+                error!("datagram_send error: {e:?}");
+                false
+            }
+        }
+    }
+
+    // Compute and send the parity shard(s) for the current FEC coding
+    // block, then clear it so the next data chunk starts a fresh one.
+    // Called once the block has filled up to `fec_k` chunks, or early (with
+    // fewer members) when the file ends mid-block.
+    fn flush_fec_block(&mut self, conn: &mut Connection) {
+        if self.fec_block.is_empty() {
+            return;
+        }
+        let group_id = self.fec_group_id;
+        let k = self.fec_block.len() as u8;
+        let base_offset = self.fec_block[0].0;
+        let payloads: Vec<Vec<u8>> = self.fec_block.iter().map(|(_, p)| p.clone()).collect();
+        let shard_len = payloads.iter().map(|p| p.len()).max().unwrap_or(0);
+
+        let parity_shards: Vec<Vec<u8>> = match self.fec_mode {
+            FecMode::Xor => vec![crate::fec::xor_parity(&payloads)],
+            FecMode::Rs => crate::fec::rs_parity(&payloads, self.fec_r as usize),
+        };
+
+        for (j, parity) in parity_shards.iter().enumerate() {
+            let mut flags = DG_FLAG_PARITY;
+            if self.fec_mode == FecMode::Rs {
+                flags |= DG_FLAG_FEC_RS;
+            }
+            let hdr = DgHdr {
+                file_id: self.file_id,
+                total_size: self.total_size,
+                offset: base_offset,
+                len: shard_len as u32,
+                flags,
+                fec_k: k,
+                parity_idx: j as u8,
+                _pad: 0,
+                group_id,
+                send_ts_ns: unix_epoch_ns(),
+            };
+            let mut packet = BytesMut::with_capacity(DgHdr::SIZE + parity.len());
+            hdr.write_to(&mut packet);
+            packet.extend_from_slice(parity);
+            let length = packet.len();
+            match conn.datagram_send(packet.freeze(), Some(length), false) {
+                Ok(()) | Err(Error::Done) => {
+                    debug!(
+                        "[FEC] sent parity group={group_id} idx={j} k={k} base_offset={base_offset} shard_len={shard_len}"
+                    );
+                }
+                Err(e) => error!("[FEC] parity datagram_send error: {e:?}"),
+            }
+        }
+
+        self.fec_group_id = self.fec_group_id.wrapping_add(1);
+        self.fec_block.clear();
+    }
+
     fn try_send_more(&mut self, conn: &mut Connection) {
-        if self.sent_bytes >= self.total_size {
+        // `self.inflight` only holds entries while `--reliable` is set
+        // (see `send_datagram_chunk`), so this is a no-op gate in Stream
+        // mode and in non-reliable Datagram mode — it only changes
+        // behavior for reliable transfers, where it's required: without
+        // it, losing the `DG_FLAG_LAST` datagram (or any tail chunk) left
+        // nothing in `retransmit_queue` to check, so the connection
+        // closed immediately instead of waiting for the receiver's NACK
+        // feedback to confirm (or correct) delivery — the one scenario
+        // the NACK/ARQ machinery exists for. `inflight` only empties once
+        // `contiguous_cursor` has caught up with everything sent (see
+        // `drain_nack_messages`), which the server now confirms
+        // explicitly once the file is complete (`send_completion_ack`).
+        if self.sent_bytes >= self.total_size
+            && self.retransmit_queue.is_empty()
+            && self.inflight.is_empty()
+        {
             let _ = conn.close(true, 0x00, b"ok");
             return;
         }
 
-        while self.sent_bytes < self.total_size && Instant::now() >= self.next_deadline {
+        self.maybe_migrate(conn);
+        self.requeue_stale_inflight();
+
+        while Instant::now() >= self.next_deadline
+            && (self.sent_bytes < self.total_size || !self.retransmit_queue.is_empty())
+        {
+            // NACKed/timed-out ranges jump the queue ahead of fresh data.
+            if self.mode == Mode::Datagram && !self.retransmit_queue.is_empty() {
+                let (off, len) = self.retransmit_queue.pop_front().unwrap();
+                let mut buf = vec![0u8; len as usize];
+                if let Err(e) = read_exact_at_cross(&self.file, &mut buf, off) {
+                    error!("[ARQ] retransmit read error off={off} len={len}: {e:?}");
+                } else if !self.send_datagram_chunk(conn, off, &buf, true, None) {
+                    break;
+                }
+                self.next_deadline += self.interval_per_chunk;
+                continue;
+            }
+
+            // Hold off on fresh data once bytes in flight reach the cwnd
+            // budget; the next ack (on_datagram_acked -> try_send_more)
+            // will free some up.
+            if self.mode == Mode::Datagram {
+                let cwnd = (self.cwnd_gain * self.filtered_rate * self.srtt.as_secs_f64())
+                    .max(self.chunk as f64);
+                if self.bytes_in_flight() as f64 >= cwnd {
+                    break;
+                }
+            }
+
             let remaining = (self.total_size - self.sent_bytes) as usize;
             let payload_size = remaining.min(self.chunk);
 
@@ -326,39 +965,28 @@ impl ClientHandler {
                         error!("file read_exact_at error: {e:?}");
                         break;
                     }
-                    // 构造 datagram: header + payload
-                    let mut packet = BytesMut::with_capacity(DgHdr::SIZE + payload_size);
-                    let hdr = DgHdr {
-                        file_id: self.file_id,
-                        total_size: self.total_size,
-                        offset: off,
-                        len: payload_size as u32,
-                        flags: if (off + payload_size as u64) >= self.total_size {
-                            1
-                        } else {
-                            0
-                        },
-                        _pad: [0, 0, 0],
-                        send_ts_ns: monotonic_ns(),
+                    let fec_stamp = if self.fec_enabled() {
+                        // A block cut short by the end of the file holds
+                        // fewer than fec_k chunks; stamp its real size, as
+                        // flush_fec_block does for the parity shards.
+                        let chunks_left =
+                            (self.total_size - off + self.chunk as u64 - 1) / self.chunk as u64;
+                        let block_k =
+                            (self.fec_block.len() as u64 + chunks_left).min(self.fec_k as u64) as u8;
+                        Some((self.fec_group_id, block_k, self.fec_block.len() as u8))
+                    } else {
+                        None
                     };
-                    hdr.write_to(&mut packet);
-                    packet.extend_from_slice(&buf);
-                    let length = packet.len();
-                    match conn.datagram_send(packet.freeze(), Some(length), false) {
-                        Ok(()) | Err(Error::Done) => {
-                            debug!(
-                                "[DGRAM] send offset={} len={} total_size={} send_ts_ns={}",
-                                hdr.offset,
-                                hdr.len,
-                                hdr.total_size,
-                                hdr.send_ts_ns
-                            );
-                            self.log_send(hdr.send_ts_ns, off, payload_size, "datagram");
-                            self.sent_bytes += payload_size as u64;
-                        }
-                        Err(e) => {
-                            error!("datagram_send error: {e:?}");
-                            break;
+                    if !self.send_datagram_chunk(conn, off, &buf, false, fec_stamp) {
+                        break;
+                    }
+                    self.sent_bytes += payload_size as u64;
+                    if self.fec_enabled() {
+                        self.fec_block.push((off, buf));
+                        let block_full = self.fec_block.len() >= self.fec_k as usize;
+                        let file_ends_mid_block = self.sent_bytes >= self.total_size;
+                        if block_full || file_ends_mid_block {
+                            self.flush_fec_block(conn);
                         }
                     }
                 }
@@ -385,6 +1013,12 @@ impl ClientHandler {
                     ) {
                         Ok(_) | Err(Error::Done) => {
                             self.log_send(monotonic_ns(), off, payload_size, "stream");
+                            self.qlog_event(
+                                "stream_sent",
+                                &format!(
+                                    "{{\"stream_id\":{sid},\"offset\":{off},\"len\":{payload_size}}}"
+                                ),
+                            );
                             self.sent_bytes += payload_size as u64;
                         }
                         Err(e) => {
@@ -406,6 +1040,78 @@ impl ClientHandler {
             }
         }
     }
+
+    // Read and reassemble NACK control messages off CTRL_STREAM_ID, acting
+    // on each one as soon as it's complete.
+    fn handle_control_stream(&mut self, conn: &mut Connection, stream_id: u64) {
+        loop {
+            let mut tmp = [0u8; 4096];
+            match conn.stream_read(stream_id, &mut tmp) {
+                Ok((n, fin)) => {
+                    if n > 0 {
+                        self.ctrl_recv_buf.extend_from_slice(&tmp[..n]);
+                        self.drain_nack_messages();
+                    }
+                    if fin {
+                        break;
+                    }
+                }
+                Err(Error::Done) => break,
+                Err(e) => {
+                    error!("[CTRL] stream read error: {e:?}");
+                    break;
+                }
+            }
+        }
+    }
+
+    // Parse complete NACK messages off `ctrl_recv_buf` as soon as they're
+    // fully buffered. Wire format: file_id: u64, contiguous_cursor: u64,
+    // count: u32, then `count` entries of (offset: u64, len: u32) — all LE.
+    fn drain_nack_messages(&mut self) {
+        const HDR_LEN: usize = 20;
+        loop {
+            if self.ctrl_recv_buf.len() < HDR_LEN {
+                return;
+            }
+            let (file_id, contiguous_cursor, count) = {
+                let mut p = &self.ctrl_recv_buf[..HDR_LEN];
+                (p.get_u64_le(), p.get_u64_le(), p.get_u32_le() as usize)
+            };
+            let needed = HDR_LEN + count * 12;
+            if self.ctrl_recv_buf.len() < needed {
+                return;
+            }
+            let mut entries = Vec::with_capacity(count);
+            {
+                let mut p = &self.ctrl_recv_buf[HDR_LEN..needed];
+                for _ in 0..count {
+                    entries.push((p.get_u64_le(), p.get_u32_le()));
+                }
+            }
+            self.ctrl_recv_buf.drain(..needed);
+
+            if file_id != self.file_id {
+                debug!("[CTRL] NACK for unknown file_id={file_id:016x}, ignoring");
+                continue;
+            }
+            info!(
+                "[CTRL] NACK for file_id={:016x}: {} missing range(s), contiguous_cursor={contiguous_cursor}",
+                file_id,
+                entries.len()
+            );
+
+            self.contiguous_cursor = self.contiguous_cursor.max(contiguous_cursor);
+            self.inflight
+                .retain(|&off, (_, payload)| off + payload.len() as u64 > self.contiguous_cursor);
+
+            for entry in entries {
+                if !self.retransmit_queue.contains(&entry) {
+                    self.retransmit_queue.push_back(entry);
+                }
+            }
+        }
+    }
 }
 
 impl TransportHandler for ClientHandler {
@@ -418,10 +1124,53 @@ impl TransportHandler for ClientHandler {
             self.total_size,
             self.file_id
         );
+        if let Some(k) = &mut self.keylog {
+            if let Ok(k2) = k.try_clone() {
+                conn.set_keylog(Box::new(k2));
+            }
+        }
+        if let Some(q) = &mut self.qlog {
+            if let Ok(q2) = q.try_clone() {
+                conn.set_qlog(Box::new(q2), "client qlog".into(), format!("id={}", conn.trace_id()));
+            }
+        }
+
+        if let Some(session) = self.resume_session.take() {
+            match conn.set_session(&session) {
+                Ok(()) => {
+                    info!(
+                        "{} resuming session, sending first chunk(s) as 0-RTT early data",
+                        conn.trace_id()
+                    );
+                    self.early_data_pending = true;
+                    self.next_deadline = Instant::now();
+                    self.try_send_more(conn);
+                }
+                Err(e) => warn!(
+                    "{} set_session failed, falling back to a full handshake: {e:?}",
+                    conn.trace_id()
+                ),
+            }
+        }
     }
 
     fn on_conn_established(&mut self, conn: &mut Connection) {
         info!("{} conn established", conn.trace_id());
+
+        if self.early_data_pending {
+            self.early_data_pending = false;
+            if !conn.is_early_data_accepted() {
+                let rejected_bytes = self.bytes_in_flight();
+                warn!(
+                    "{} 0-RTT rejected, replaying {rejected_bytes} unacknowledged early-data byte(s)",
+                    conn.trace_id()
+                );
+                self.sent_bytes = self.sent_bytes.saturating_sub(rejected_bytes);
+                self.inflight_chunks.clear();
+                self.fec_block.clear();
+            }
+        }
+
         // 如果你们 fork 有 open_uni()，可在这里：
         let sid = conn.stream_bidi_new(3, true).unwrap_or(0);
         self.stream_id = Some(sid);
@@ -435,6 +1184,9 @@ impl TransportHandler for ClientHandler {
             conn.trace_id(),
             self.sent_bytes
         );
+        // Catch a session ticket that arrived without a NEW_TOKEN frame.
+        self.maybe_persist_session(conn);
+        self.ctx.borrow_mut().finish = true;
     }
 
     fn on_stream_writable(&mut self, conn: &mut Connection, _stream_id: u64) {
@@ -444,8 +1196,10 @@ impl TransportHandler for ClientHandler {
         }
     }
 
-    fn on_stream_readable(&mut self, _conn: &mut Connection, _stream_id: u64) {
-        // 客户端无需读
+    fn on_stream_readable(&mut self, conn: &mut Connection, stream_id: u64) {
+        if stream_id == CTRL_STREAM_ID {
+            self.handle_control_stream(conn, stream_id);
+        }
     }
 
     fn on_stream_created(&mut self, conn: &mut Connection, sid: u64) {
@@ -460,19 +1214,36 @@ impl TransportHandler for ClientHandler {
         info!("{} stream {} closed", conn.trace_id(), sid);
     }
 
-    fn on_new_token(&mut self, _conn: &mut Connection, _token: Vec<u8>) {}
+    fn on_new_token(&mut self, conn: &mut Connection, _token: Vec<u8>) {
+        self.maybe_persist_session(conn);
+    }
 
     // datagram 事件用于继续推进发送
     fn on_datagram_acked(&mut self, conn: &mut Connection) {
+        self.qlog_event(
+            "datagram_acked",
+            &format!("{{\"trace_id\":\"{}\"}}", conn.trace_id()),
+        );
+        self.on_chunk_acked();
         self.try_send_more(conn);
     }
     fn on_datagram_drop(&mut self, conn: &mut Connection) {
+        self.qlog_event(
+            "datagram_dropped",
+            &format!("{{\"trace_id\":\"{}\"}}", conn.trace_id()),
+        );
+        self.on_chunk_lost();
         self.try_send_more(conn);
     }
     fn on_datagram_longtime(&mut self, conn: &mut Connection) {
         self.try_send_more(conn);
     }
     fn on_datagram_losted(&mut self, conn: &mut Connection) {
+        self.qlog_event(
+            "datagram_lost",
+            &format!("{{\"trace_id\":\"{}\"}}", conn.trace_id()),
+        );
+        self.on_chunk_lost();
         self.try_send_more(conn);
     }
     fn on_datagram_recvived(&mut self, _conn: &mut Connection) {}
@@ -484,9 +1255,37 @@ fn monotonic_ns() -> u64 {
     START.with(|s| s.elapsed().as_nanos() as u64)
 }
 
-fn main() -> Result<()> {
-    let opt = ClientOpt::parse();
-    env_logger::builder().filter_level(opt.log_level).init();
+// Wall-clock nanos since the Unix epoch, for `DgHdr::send_ts_ns`. The
+// server computes one-way latency as its own receive time minus this
+// field, so it has to be something the two processes' clocks actually
+// agree on — `monotonic_ns()` above zeroes against whenever this process
+// happened to first call it, which the server has no way to relate to its
+// own `Instant`.
+fn unix_epoch_ns() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// Run the `send` subcommand: connect to `opt.connect_to` and transfer
+/// `opt.in_file` until the connection closes. Dispatches to the
+/// completion-based `runtime_async` driver when `--runtime async` is
+/// given; otherwise drives the mio readiness loop below directly.
+pub fn run(opt: ClientOpt) -> Result<()> {
+    env_logger::builder().filter_level(opt.log_level).try_init().ok();
+
+    if opt.runtime == RuntimeMode::Async {
+        if opt.migrate_after.is_some() {
+            // `runtime_async` never calls `Client::service_migration_request`
+            // (see its module docs), so today --migrate-after would just sit
+            // in "requested but never serviced" forever under this driver.
+            // Fail fast instead of silently hanging.
+            return Err("--migrate-after is not supported with --runtime async yet; use --runtime mio".into());
+        }
+        return crate::runtime_async::run(opt);
+    }
 
     let mut cli = Client::new(&opt)?;
     cli.endpoint.connect(
@@ -504,15 +1303,15 @@ fn main() -> Result<()> {
         if cli.finish() {
             break;
         }
+        cli.service_migration_request()?;
 
         cli.poll.poll(&mut events, cli.endpoint.timeout())?;
         for ev in events.iter() {
             if ev.is_readable() {
-                cli.process_read_event(ev)?;
+                cli.process_read_event(ev.token())?;
             }
         }
         cli.endpoint.on_timeout(Instant::now());
     }
     Ok(())
-}
-pub(crate) mod qskt;
\ No newline at end of file
+}
\ No newline at end of file
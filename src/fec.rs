@@ -0,0 +1,273 @@
+//! GF(2^8) arithmetic and a systematic Reed–Solomon-style parity code,
+//! shared by the client's FEC parity emission (`client::try_send_more`)
+//! and the server's shard reconstruction (`server::handle_datagram`).
+//!
+//! The code is systematic: each of the `k` data shards in a block is sent
+//! unmodified, and up to `r` parity shards are generated from a Vandermonde
+//! matrix so that any `k` of the `k + r` shards are enough to recover the
+//! rest. XOR mode is the `r == 1`, generator-value-1 special case of the
+//! same scheme, computed directly rather than through the field to keep the
+//! common single-parity path cheap and obviously correct.
+
+fn gf_tables() -> &'static (Vec<u8>, Vec<u8>) {
+    static TABLES: std::sync::OnceLock<(Vec<u8>, Vec<u8>)> = std::sync::OnceLock::new();
+    TABLES.get_or_init(|| {
+        // exp[i] = generator^i, log[generator^i] = i, generator = 2, with
+        // the reduction polynomial 0x11d (x^8 + x^4 + x^3 + x^2 + 1).
+        let mut exp = vec![0u8; 512];
+        let mut log = vec![0u8; 256];
+        let mut x: u16 = 1;
+        for i in 0..255usize {
+            exp[i] = x as u8;
+            log[x as usize] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= 0x11d;
+            }
+        }
+        for i in 255..512 {
+            exp[i] = exp[i - 255];
+        }
+        (exp, log)
+    })
+}
+
+fn gf_mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    let (exp, log) = gf_tables();
+    exp[log[a as usize] as usize + log[b as usize] as usize]
+}
+
+fn gf_pow(a: u8, p: u32) -> u8 {
+    if a == 0 {
+        return 0;
+    }
+    let (exp, log) = gf_tables();
+    let l = (log[a as usize] as u32 * p) % 255;
+    exp[l as usize]
+}
+
+fn gf_inv(a: u8) -> u8 {
+    let (exp, log) = gf_tables();
+    exp[(255 - log[a as usize] as u32) as usize]
+}
+
+/// The Vandermonde x-value used for parity row `j` (0-indexed). Row 0 uses
+/// x=1, which is exactly why a single parity shard (r=1) degenerates to a
+/// plain XOR of the data shards.
+fn parity_x(j: usize) -> u8 {
+    (j + 1) as u8
+}
+
+/// Byte-wise XOR of `shards`, zero-extended to the longest one.
+pub fn xor_parity(shards: &[Vec<u8>]) -> Vec<u8> {
+    let len = shards.iter().map(|s| s.len()).max().unwrap_or(0);
+    let mut out = vec![0u8; len];
+    for shard in shards {
+        for (b, &byte) in shard.iter().enumerate() {
+            out[b] ^= byte;
+        }
+    }
+    out
+}
+
+/// Generate `r` parity shards from `k` data shards using the systematic
+/// Vandermonde code described above.
+pub fn rs_parity(data_shards: &[Vec<u8>], r: usize) -> Vec<Vec<u8>> {
+    let k = data_shards.len();
+    let shard_len = data_shards.iter().map(|s| s.len()).max().unwrap_or(0);
+    let mut parity = vec![vec![0u8; shard_len]; r];
+    for (j, out) in parity.iter_mut().enumerate() {
+        let x = parity_x(j);
+        for (i, shard) in data_shards.iter().enumerate() {
+            let coeff = gf_pow(x, i as u32);
+            if coeff == 0 {
+                continue;
+            }
+            for (b, &byte) in shard.iter().enumerate() {
+                out[b] ^= gf_mul(coeff, byte);
+            }
+        }
+    }
+    parity
+}
+
+/// Recover missing data shards given every data shard we did receive
+/// (`Some`) or didn't (`None`), plus the parity shards received, keyed by
+/// their parity row index `j` (0-based, matching `parity_x`).
+///
+/// Returns the recovered bytes for each originally-`None` entry, in the
+/// order of their indices in `data_shards`. `None` is returned for the whole call
+/// if there aren't enough parity shards to solve for all the gaps.
+pub fn rs_recover(
+    data_shards: &[Option<Vec<u8>>],
+    parity_shards: &[(usize, Vec<u8>)],
+    shard_len: usize,
+) -> Option<Vec<Vec<u8>>> {
+    let k = data_shards.len();
+    let missing: Vec<usize> = (0..k).filter(|&i| data_shards[i].is_none()).collect();
+    if missing.is_empty() {
+        return Some(Vec::new());
+    }
+    if parity_shards.len() < missing.len() {
+        return None;
+    }
+    let used_parity = &parity_shards[..missing.len()];
+
+    // Build the coefficient matrix M (m x m) for the unknowns, and the
+    // right-hand side per byte position: rhs = parity XOR (known data
+    // contributions).
+    let m = missing.len();
+    let mut matrix = vec![vec![0u8; m]; m];
+    for (row, &(j, _)) in used_parity.iter().enumerate() {
+        let x = parity_x(j);
+        for (col, &data_idx) in missing.iter().enumerate() {
+            matrix[row][col] = gf_pow(x, data_idx as u32);
+        }
+    }
+    let inv = invert_matrix(&matrix)?;
+
+    let mut rhs = vec![vec![0u8; shard_len]; m];
+    for (row, &(j, ref parity)) in used_parity.iter().enumerate() {
+        let x = parity_x(j);
+        rhs[row][..parity.len().min(shard_len)].copy_from_slice(&parity[..parity.len().min(shard_len)]);
+        for (data_idx, shard) in data_shards.iter().enumerate() {
+            if let Some(shard) = shard {
+                let coeff = gf_pow(x, data_idx as u32);
+                if coeff == 0 {
+                    continue;
+                }
+                for (b, &byte) in shard.iter().enumerate() {
+                    rhs[row][b] ^= gf_mul(coeff, byte);
+                }
+            }
+        }
+    }
+
+    let mut recovered = vec![vec![0u8; shard_len]; m];
+    for b in 0..shard_len {
+        for row in 0..m {
+            let mut acc = 0u8;
+            for col in 0..m {
+                acc ^= gf_mul(inv[row][col], rhs[col][b]);
+            }
+            recovered[row][b] = acc;
+        }
+    }
+    Some(recovered)
+}
+
+/// Gauss-Jordan inversion of a square matrix over GF(2^8).
+fn invert_matrix(m: &[Vec<u8>]) -> Option<Vec<Vec<u8>>> {
+    let n = m.len();
+    let mut a: Vec<Vec<u8>> = m.to_vec();
+    let mut inv: Vec<Vec<u8>> = (0..n)
+        .map(|i| (0..n).map(|j| if i == j { 1 } else { 0 }).collect())
+        .collect();
+
+    for col in 0..n {
+        let pivot_row = (col..n).find(|&r| a[r][col] != 0)?;
+        a.swap(col, pivot_row);
+        inv.swap(col, pivot_row);
+
+        let pivot_inv = gf_inv(a[col][col]);
+        for v in a[col].iter_mut() {
+            *v = gf_mul(*v, pivot_inv);
+        }
+        for v in inv[col].iter_mut() {
+            *v = gf_mul(*v, pivot_inv);
+        }
+
+        for row in 0..n {
+            if row == col {
+                continue;
+            }
+            let factor = a[row][col];
+            if factor == 0 {
+                continue;
+            }
+            for c in 0..n {
+                a[row][c] ^= gf_mul(factor, a[col][c]);
+                inv[row][c] ^= gf_mul(factor, inv[col][c]);
+            }
+        }
+    }
+    Some(inv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shards(vals: &[&[u8]]) -> Vec<Vec<u8>> {
+        vals.iter().map(|v| v.to_vec()).collect()
+    }
+
+    #[test]
+    fn gf_mul_and_inv_are_consistent() {
+        for a in 1..=255u8 {
+            assert_eq!(gf_mul(a, gf_inv(a)), 1);
+        }
+        assert_eq!(gf_mul(0, 5), 0);
+        assert_eq!(gf_mul(5, 0), 0);
+    }
+
+    #[test]
+    fn xor_parity_matches_plain_xor() {
+        let data = shards(&[&[0x0f, 0x01], &[0xf0, 0x02], &[0x01, 0x04]]);
+        let parity = xor_parity(&data);
+        assert_eq!(parity, vec![0x0f ^ 0xf0 ^ 0x01, 0x01 ^ 0x02 ^ 0x04]);
+    }
+
+    #[test]
+    fn rs_parity_with_r1_equals_xor_parity() {
+        // r=1 (row 0, x=1) degenerates rs_parity to plain XOR, confirming
+        // the "xor is the r=1 special case" doc comment above.
+        let data = shards(&[&[1, 2, 3], &[4, 5, 6], &[7, 8, 9]]);
+        let parity = rs_parity(&data, 1);
+        assert_eq!(parity[0], xor_parity(&data));
+    }
+
+    #[test]
+    fn rs_recover_reconstructs_single_missing_data_shard() {
+        let data = shards(&[&[10, 20, 30], &[40, 50, 60], &[70, 80, 90]]);
+        let parity = rs_parity(&data, 1);
+
+        let with_gap: Vec<Option<Vec<u8>>> =
+            vec![Some(data[0].clone()), None, Some(data[2].clone())];
+        let recovered = rs_recover(&with_gap, &[(0, parity[0].clone())], 3)
+            .expect("should recover with exactly enough parity");
+        assert_eq!(recovered, vec![data[1].clone()]);
+    }
+
+    #[test]
+    fn rs_recover_reconstructs_multiple_missing_data_shards() {
+        let data = shards(&[&[1, 2], &[3, 4], &[5, 6], &[7, 8]]);
+        let parity = rs_parity(&data, 2);
+
+        let with_gaps: Vec<Option<Vec<u8>>> =
+            vec![Some(data[0].clone()), None, None, Some(data[3].clone())];
+        let used_parity = vec![(0, parity[0].clone()), (1, parity[1].clone())];
+        let recovered =
+            rs_recover(&with_gaps, &used_parity, 2).expect("two parity shards recover two gaps");
+        assert_eq!(recovered, vec![data[1].clone(), data[2].clone()]);
+    }
+
+    #[test]
+    fn rs_recover_returns_none_when_parity_is_insufficient() {
+        let data = shards(&[&[1, 2], &[3, 4], &[5, 6]]);
+        let parity = rs_parity(&data, 1);
+
+        let with_gaps: Vec<Option<Vec<u8>>> = vec![None, None, Some(data[2].clone())];
+        assert!(rs_recover(&with_gaps, &[(0, parity[0].clone())], 2).is_none());
+    }
+
+    #[test]
+    fn rs_recover_is_noop_when_nothing_missing() {
+        let data = shards(&[&[1, 2], &[3, 4]]);
+        let all_present: Vec<Option<Vec<u8>>> = data.iter().cloned().map(Some).collect();
+        assert_eq!(rs_recover(&all_present, &[], 2), Some(Vec::new()));
+    }
+}
@@ -0,0 +1,8 @@
+//! Shared library behind the `tquic-datagram-example` binary: the `send`
+//! (client) and `recv` (server) subcommand implementations, exposed here so
+//! the integration tests in `tests/` can drive both ends in-process.
+pub mod client;
+pub mod fec;
+pub mod runtime_async;
+pub mod server;
+pub mod qskt;
@@ -0,0 +1,30 @@
+// TQUIC datagram file-transfer example: a single binary with `send`/`recv`
+// subcommands instead of two separate tools sharing copy-pasted option
+// parsing.
+use clap::{Parser, Subcommand};
+
+use tquic_datagram_example::qskt::Result;
+use tquic_datagram_example::{client, server};
+
+#[derive(Parser, Debug)]
+#[clap(name = "tquic-datagram-example")]
+struct Cli {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Send a file to a server (client role)
+    Send(client::ClientOpt),
+    /// Receive files from clients (server role)
+    Recv(server::ServerOpt),
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    match cli.command {
+        Command::Send(opt) => client::run(opt),
+        Command::Recv(opt) => server::run(opt),
+    }
+}
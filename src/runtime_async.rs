@@ -0,0 +1,77 @@
+//! Completion-based async driver for the `send` subcommand, selected with
+//! `--runtime async` as an alternative to `client::run`'s mio readiness
+//! loop (in the spirit of compio's QUIC integration). Gated behind the
+//! `async-runtime` feature so the default `mio`-only build never pulls in
+//! a second runtime.
+//!
+//! This reuses `client::Client`/`ClientHandler` unchanged: the `Endpoint`,
+//! `Config`, and `QuicSocket` are built exactly as `client::run` builds
+//! them, and all transfer logic still lives in `ClientHandler`'s
+//! `TransportHandler` callbacks. What changes is the outer shape of the
+//! driving loop — the readiness wait becomes an `.await` point on a single
+//! compio task, with `process_connections` and `on_timeout` run around it,
+//! instead of a `mio::Poll::poll` call that's re-entered on every wakeup.
+//!
+//! One honest caveat: true zero-copy, batched datagram receive needs a
+//! compio-native transport underneath `qskt::QuicSocket`, which doesn't
+//! exist yet. Until that lands, this driver approximates the completion
+//! model by awaiting a short `compio::time::sleep` instead of blocking a
+//! whole thread in `mio::Poll::poll`, then draining the same non-blocking
+//! socket `client::run` already uses. That's enough to let `--runtime
+//! async` share a reactor with other async work in the same process
+//! instead of owning a dedicated OS thread — the actual motivation for
+//! offering it — without faking a socket layer that isn't there.
+
+#[cfg(not(feature = "async-runtime"))]
+pub fn run(_opt: crate::client::ClientOpt) -> crate::qskt::Result<()> {
+    Err("built without the `async-runtime` feature; rebuild with \
+         `--features async-runtime` to use --runtime async"
+        .into())
+}
+
+#[cfg(feature = "async-runtime")]
+pub fn run(opt: crate::client::ClientOpt) -> crate::qskt::Result<()> {
+    compio::runtime::Runtime::new()
+        .map_err(|e| format!("compio runtime init failed: {e}"))?
+        .block_on(run_inner(opt))
+}
+
+#[cfg(feature = "async-runtime")]
+async fn run_inner(opt: crate::client::ClientOpt) -> crate::qskt::Result<()> {
+    use std::time::{Duration, Instant};
+
+    use crate::client::Client;
+
+    // Just a cap on how long we ever sleep before re-checking readability,
+    // not a protocol timeout.
+    const MAX_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+    let mut cli = Client::new(&opt)?;
+    cli.endpoint.connect(
+        cli.sock.local_addr(),
+        opt.connect_to,
+        None,
+        None,
+        None,
+        None,
+    )?;
+
+    loop {
+        cli.endpoint.process_connections()?;
+        if cli.finish() {
+            break;
+        }
+
+        let wait = cli
+            .endpoint
+            .timeout()
+            .unwrap_or(MAX_POLL_INTERVAL)
+            .min(MAX_POLL_INTERVAL);
+        compio::time::sleep(wait).await;
+
+        // `Client::new` registers exactly one socket, always at token 0.
+        cli.process_read_event(mio::Token(0))?;
+        cli.endpoint.on_timeout(Instant::now());
+    }
+    Ok(())
+}
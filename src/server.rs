@@ -1,25 +1,28 @@
-// tools/src/bin/server.rs
+// src/server.rs — `recv` subcommand
 // TQUIC Server: 接收真实文件（Datagram/Stream），丰富日志，CSV 可选。
 // 依赖：tquic, bytes, clap, log, env_logger, mio
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fs::{File, OpenOptions};
-use std::io::{Seek, SeekFrom, Write};
+use std::io::Write;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::time::Instant;
 
-use bytes::Buf; // 用于小端读取
-use clap::Parser;
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut}; // 用于小端读写
+use clap::Args;
 use log::{debug, error, info, warn};
 use mio::event::Event;
 
 use tquic::{Config, Connection, Endpoint, Error, PacketInfo, TlsConfig, TransportHandler, CongestionControlAlgorithm};
-use qskt::{QuicSocket, Result};
 
-#[derive(Parser, Debug, Clone)]
-#[clap(name = "server")]
+use crate::qskt::{QuicSocket, Result};
+
+#[derive(Args, Debug, Clone)]
 pub struct ServerOpt {
     /// TLS certificate (PEM)
     #[clap(short, long = "cert", default_value = "./cert.crt")]
@@ -74,22 +77,74 @@ pub struct ServerOpt {
     pub cca: Option<String>,
 }
 
+#[cfg(unix)]
+fn write_all_at_cross(f: &File, buf: &[u8], off: u64) -> std::io::Result<()> {
+    f.write_all_at(buf, off)
+}
+
+#[cfg(windows)]
+fn write_all_at_cross(f: &File, mut buf: &[u8], off: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    let mut done = 0u64;
+    while !buf.is_empty() {
+        let n = f.seek_write(buf, off + done)?;
+        if n == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::WriteZero));
+        }
+        done += n as u64;
+        buf = &buf[n..];
+    }
+    Ok(())
+}
+
+// Used to re-read already-written data shards when an FEC block needs
+// reconstructing; mirrors the client's own read_exact_at_cross.
+#[cfg(unix)]
+fn read_exact_at_cross(f: &File, buf: &mut [u8], off: u64) -> std::io::Result<()> {
+    f.read_exact_at(buf, off)
+}
+
+#[cfg(windows)]
+fn read_exact_at_cross(f: &File, mut buf: &mut [u8], off: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    let mut done = 0;
+    while done < buf.len() {
+        let n = f.seek_read(&mut buf[done..], off + done as u64)?;
+        if n == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+        }
+        done += n;
+    }
+    Ok(())
+}
+
 const MAX_BUF_SIZE: usize = 64 * 1024;
 
-// Datagram header (40 bytes aligned)
+// Datagram header (44 bytes aligned)
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 struct DgHdr {
     file_id: u64,
     total_size: u64,
+    // Real file offset for data shards; base offset of shard 0 of the
+    // coding block for PARITY shards.
     offset: u64,
+    // Real payload length for data shards; (zero-padded) shard length of
+    // the coding block for PARITY shards.
     len: u32,
-    flags: u8,       // bit0: last
-    _pad: [u8; 3],   // align
+    flags: u8, // bit0: last, bit1: retransmit, bit2: parity, bit3: RS mode
+    // k for this datagram's FEC coding block, 0 if FEC is disabled.
+    fec_k: u8,
+    // Shard index within the block for data shards; parity row index for
+    // PARITY shards.
+    parity_idx: u8,
+    _pad: u8,
+    // FEC coding block id.
+    group_id: u32,
     send_ts_ns: u64, // for E2E latency
 }
 impl DgHdr {
-    const SIZE: usize = 40;
+    const SIZE: usize = 44;
     fn parse(buf: &[u8]) -> Option<(DgHdr, &[u8])> {
         if buf.len() < Self::SIZE {
             return None;
@@ -100,9 +155,10 @@ impl DgHdr {
         let offset = p.get_u64_le();
         let len = p.get_u32_le();
         let flags = p.get_u8();
+        let fec_k = p.get_u8();
+        let parity_idx = p.get_u8();
         let _pad0 = p.get_u8();
-        let _pad1 = p.get_u8();
-        let _pad2 = p.get_u8();
+        let group_id = p.get_u32_le();
         let send_ts_ns = p.get_u64_le();
         let payload = &buf[Self::SIZE..];
         Some((
@@ -112,7 +168,10 @@ impl DgHdr {
                 offset,
                 len,
                 flags,
-                _pad: [0, 0, 0],
+                fec_k,
+                parity_idx,
+                _pad: 0,
+                group_id,
                 send_ts_ns,
             },
             payload,
@@ -121,9 +180,51 @@ impl DgHdr {
     fn is_last(&self) -> bool {
         self.flags & 0x01 != 0
     }
+    fn is_parity(&self) -> bool {
+        self.flags & 0x04 != 0
+    }
+}
+
+// Control-stream message asking the client to re-send specific byte ranges
+// of a datagram transfer. Sent over a dedicated reliable bidi stream since
+// DATAGRAM frames themselves may be dropped.
+//
+// Wire format (all little-endian): file_id: u64, contiguous_cursor: u64,
+// count: u32, then `count` entries of (offset: u64, len: u32).
+struct NackMsg {
+    file_id: u64,
+    // highest offset below which we've received every byte; lets the
+    // client drop in-flight bookkeeping it no longer needs.
+    contiguous_cursor: u64,
+    missing: Vec<(u64, u32)>,
 }
 
-struct Server {
+impl NackMsg {
+    fn encode(&self) -> Bytes {
+        let mut b = BytesMut::with_capacity(20 + self.missing.len() * 12);
+        b.put_u64_le(self.file_id);
+        b.put_u64_le(self.contiguous_cursor);
+        b.put_u32_le(self.missing.len() as u32);
+        for (offset, len) in &self.missing {
+            b.put_u64_le(*offset);
+            b.put_u32_le(*len);
+        }
+        b.freeze()
+    }
+}
+
+// Reliable control stream the server opens (once per connection) to carry
+// NACKs; the client recognizes it by id rather than by the data stream it
+// itself creates for stream-mode transfers.
+const CTRL_STREAM_ID: u64 = 5;
+
+// Cap on how many NACK rounds we'll send per file before giving up on
+// chasing loss, logging what's still missing and closing the connection.
+const MAX_NACK_ROUNDS: u32 = 8;
+// Minimum spacing between NACK rounds for the same file.
+const NACK_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+
+pub struct Server {
     endpoint: Endpoint,
     poll: mio::Poll,
     sock: Rc<QuicSocket>,
@@ -131,7 +232,7 @@ struct Server {
 }
 
 impl Server {
-    fn new(opt: &ServerOpt) -> Result<Self> {
+    pub fn new(opt: &ServerOpt) -> Result<Self> {
         let mut cfg = Config::new()?;
         cfg.set_max_idle_timeout(opt.idle_timeout);
 
@@ -185,6 +286,12 @@ impl Server {
         })
     }
 
+    /// The address the listening socket actually bound to (useful when
+    /// `opt.listen` asked for an ephemeral port, e.g. in tests).
+    pub fn local_addr(&self) -> SocketAddr {
+        self.sock.local_addr()
+    }
+
     fn process_read_event(&mut self, event: &Event) -> Result<()> {
         loop {
             let (len, local, remote) = match self.sock.recv_from(&mut self.recv_buf, event.token()) {
@@ -209,12 +316,184 @@ impl Server {
     }
 }
 
+// Tracks which byte intervals of a file have actually been written, so
+// completion can be determined from real coverage instead of file length
+// (datagrams arrive out of order/lossy, so a seek to a high offset alone
+// tells us nothing about the bytes in between).
+#[derive(Default)]
+struct RangeTracker {
+    // start -> end of non-overlapping, half-open [start, end) intervals
+    ranges: BTreeMap<u64, u64>,
+}
+
+impl RangeTracker {
+    fn new() -> Self {
+        Self {
+            ranges: BTreeMap::new(),
+        }
+    }
+
+    // Record that [offset, offset+len) has been written, merging it with
+    // any existing interval it overlaps or abuts.
+    fn insert(&mut self, offset: u64, len: u64) {
+        if len == 0 {
+            return;
+        }
+        let mut start = offset;
+        let mut end = offset + len;
+
+        // Any interval starting at or before `end` that could overlap/abut:
+        // look at the one starting before `start` (may extend into us) plus
+        // everything starting in [start, end].
+        let mut to_remove = Vec::new();
+        for (&s, &e) in self.ranges.range(..=end) {
+            if e < start {
+                continue;
+            }
+            // overlaps or abuts [start, end)
+            start = start.min(s);
+            end = end.max(e);
+            to_remove.push(s);
+        }
+        for s in to_remove {
+            self.ranges.remove(&s);
+        }
+        self.ranges.insert(start, end);
+    }
+
+    // True once exactly the single interval [0, total) has been recorded.
+    fn is_complete(&self, total: u64) -> bool {
+        self.ranges.len() == 1 && self.ranges.get(&0) == Some(&total)
+    }
+
+    // Highest offset below which every byte has been received.
+    fn contiguous_upto(&self) -> u64 {
+        self.ranges.get(&0).copied().unwrap_or(0)
+    }
+
+    // The gaps still missing within [0, total).
+    fn missing_ranges(&self, total: u64) -> Vec<(u64, u64)> {
+        let mut missing = Vec::new();
+        let mut cursor = 0u64;
+        for (&s, &e) in self.ranges.iter() {
+            if s > cursor {
+                missing.push((cursor, s));
+            }
+            cursor = cursor.max(e);
+        }
+        if cursor < total {
+            missing.push((cursor, total));
+        }
+        missing
+    }
+}
+
+// Width of the sliding window used to turn cumulative byte counts into an
+// instantaneous goodput sample.
+const RATE_WINDOW_NS: u64 = 200_000_000; // 200ms
+// An inter-arrival gap wider than this means the sender (not the path) is
+// the bottleneck, so the sample shouldn't be read as the link's capacity.
+const APP_LIMITED_GAP_NS: u64 = 50_000_000; // 50ms
+
+// Sliding-window delivery-rate estimator shared by the datagram and stream
+// receive paths: feed it (timestamp, cumulative bytes) on every read and it
+// reports instantaneous goodput plus an app-limited flag.
+#[derive(Default)]
+struct RateEstimator {
+    window: VecDeque<(u64, u64)>,
+    last_sample_ts_ns: Option<u64>,
+    peak_bytes_per_sec: f64,
+    rate_sum: f64,
+    rate_samples: u64,
+}
+
+impl RateEstimator {
+    fn new() -> Self {
+        Self::default()
+    }
+
+    fn sample(&mut self, now_ns: u64, cumulative_bytes: u64) -> Option<(f64, bool)> {
+        let app_limited = match self.last_sample_ts_ns {
+            Some(prev) if now_ns > prev => now_ns - prev > APP_LIMITED_GAP_NS,
+            _ => false,
+        };
+        self.last_sample_ts_ns = Some(now_ns);
+
+        self.window.push_back((now_ns, cumulative_bytes));
+        while let Some(&(ts, _)) = self.window.front() {
+            if now_ns.saturating_sub(ts) > RATE_WINDOW_NS {
+                self.window.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        let &(oldest_ts, oldest_bytes) = self.window.front()?;
+        if now_ns <= oldest_ts {
+            return None;
+        }
+        let bytes = (cumulative_bytes.saturating_sub(oldest_bytes)) as f64;
+        let secs = (now_ns - oldest_ts) as f64 / 1e9;
+        let rate = bytes / secs;
+
+        self.peak_bytes_per_sec = self.peak_bytes_per_sec.max(rate);
+        self.rate_sum += rate;
+        self.rate_samples += 1;
+        Some((rate, app_limited))
+    }
+
+    fn mean_bytes_per_sec(&self) -> f64 {
+        if self.rate_samples == 0 {
+            0.0
+        } else {
+            self.rate_sum / self.rate_samples as f64
+        }
+    }
+}
+
+// State for one in-progress FEC coding block: which of its `k` data shards
+// we've seen written to disk (via the normal datagram path) and which
+// parity shards have arrived, so a lost data shard can be reconstructed
+// without waiting on a NACK round trip.
+struct FecBlockState {
+    k: u8,
+    // Offset of shard 0; authoritative once any parity shard for this
+    // block arrives (parity always carries it), best-effort before that.
+    base_offset: u64,
+    // Zero-padded length shards were encoded at.
+    shard_len: usize,
+    data_seen: Vec<bool>,
+    parity: Vec<(usize, Vec<u8>)>,
+    resolved: bool,
+}
+
 // Per-file receiving state (Datagram)
 struct FileRx {
     path: PathBuf,
     f: File,
     total: u64,
     received_bytes: u64,
+    ranges: RangeTracker,
+    nack_rounds_sent: u32,
+    // Set once `maybe_send_nack` has given up chasing loss for this file
+    // (see `MAX_NACK_ROUNDS`), so the close-on-giving-up path only fires
+    // once instead of on every subsequent timeout tick.
+    gave_up: bool,
+    last_nack_check: Instant,
+    rate: RateEstimator,
+    first_recv_ts_ns: Option<u64>,
+    latency_sum_ns: u128,
+    latency_count: u64,
+    fec_blocks: HashMap<u32, FecBlockState>,
+}
+
+impl FileRx {
+    // Positional write that leaves the file's cursor untouched, so a single
+    // open `File` can safely be written from multiple offsets (and, later,
+    // multiple threads) without an implicit seek/write ordering dependency.
+    fn write_at(&self, offset: u64, buf: &[u8]) -> std::io::Result<()> {
+        write_all_at_cross(&self.f, buf, offset)
+    }
 }
 
 struct ServerHandler {
@@ -277,6 +556,22 @@ impl ServerHandler {
         }
     }
 
+    // Emit an application-level qlog event into the same qlog stream the
+    // transport library writes to, so datagram/stream activity can be
+    // correlated with the transport's own events when replayed in a qlog
+    // viewer. Written as a JSON Text Sequence record (RFC 7464): an ASCII
+    // Record Separator followed by one JSON object per line.
+    fn qlog_event(&mut self, name: &str, data: &str) {
+        if let Some(q) = &mut self.qlog {
+            let now_ns = monotonic_ns();
+            let _ = writeln!(
+                q,
+                "\u{1e}{{\"time\":{now_ns},\"name\":\"{name}\",\"data\":{data}}}"
+            );
+            let _ = q.flush();
+        }
+    }
+
     fn get_or_create_dg_file(&mut self, file_id: u64, total: u64) -> std::io::Result<&mut FileRx> {
         if !self.dgram_files.contains_key(&file_id) {
             let filename = format!("dgram_{file_id:016x}_size{total}.bin");
@@ -293,24 +588,165 @@ impl ServerHandler {
                     f,
                     total,
                     received_bytes: 0,
+                    ranges: RangeTracker::new(),
+                    nack_rounds_sent: 0,
+                    gave_up: false,
+                    last_nack_check: Instant::now(),
+                    rate: RateEstimator::new(),
+                    first_recv_ts_ns: None,
+                    latency_sum_ns: 0,
+                    latency_count: 0,
+                    fec_blocks: HashMap::new(),
                 },
             );
         }
         Ok(self.dgram_files.get_mut(&file_id).unwrap())
     }
 
-    fn finish_if_complete_dg(&mut self, file_id: u64) {
-        if let Some(rx) = self.dgram_files.get(&file_id) {
-            if let Ok(meta) = rx.f.metadata() {
-                if meta.len() >= rx.total {
-                    info!(
-                        "[DGRAM] file_id={:016x} completed: {} bytes -> {}",
-                        file_id,
-                        rx.total,
-                        rx.path.display()
-                    );
-                }
+    fn finish_if_complete_dg(&mut self, file_id: u64, conn: &mut Connection) {
+        let is_complete = match self.dgram_files.get(&file_id) {
+            Some(rx) => rx.ranges.is_complete(rx.total),
+            None => return,
+        };
+
+        if is_complete {
+            let summary = self.dgram_files.get(&file_id).map(|rx| {
+                let now_ns = monotonic_ns();
+                let duration_s =
+                    (now_ns.saturating_sub(rx.first_recv_ts_ns.unwrap_or(now_ns))) as f64 / 1e9;
+                let mean_latency_ns = if rx.latency_count > 0 {
+                    rx.latency_sum_ns / rx.latency_count as u128
+                } else {
+                    0
+                };
+                (
+                    rx.total,
+                    rx.path.clone(),
+                    duration_s,
+                    rx.rate.mean_bytes_per_sec(),
+                    rx.rate.peak_bytes_per_sec,
+                    mean_latency_ns,
+                )
+            });
+            if let Some((total, path, duration_s, mean, peak, mean_latency_ns)) = summary {
+                info!(
+                    "[DGRAM] file_id={:016x} completed: {total} bytes -> {} ({duration_s:.3}s, mean={mean:.0}B/s, peak={peak:.0}B/s, mean_latency={}us)",
+                    file_id,
+                    path.display(),
+                    mean_latency_ns / 1000,
+                );
+                self.qlog_event(
+                    "file_completed",
+                    &format!(
+                        "{{\"file_id\":\"{file_id:016x}\",\"total_bytes\":{total},\"duration_s\":{duration_s:.3}}}"
+                    ),
+                );
             }
+            // `maybe_send_nack` never reports anything once the transfer
+            // is complete (there's nothing missing to report), so without
+            // this the client's reliable-mode ARQ loop has no way to
+            // learn delivery actually finished — it would keep waiting on
+            // a NACK that will never come. Tell it explicitly.
+            self.send_completion_ack(file_id, conn);
+        } else {
+            self.maybe_send_nack(file_id, conn, true);
+        }
+    }
+
+    // Unlike maybe_send_nack, always sends — including when nothing is
+    // missing — so the client's contiguous_cursor tracking can observe a
+    // clean finish and release its in-flight bookkeeping (see
+    // ClientHandler::try_send_more's close gate).
+    fn send_completion_ack(&mut self, file_id: u64, conn: &mut Connection) {
+        let Some(rx) = self.dgram_files.get_mut(&file_id) else {
+            return;
+        };
+        if conn.stream_bidi_new(CTRL_STREAM_ID, true).is_err() {
+            // already open, or stream machinery declined a second create; try
+            // to write to it regardless since it may simply already exist
+        }
+        let msg = NackMsg {
+            file_id,
+            contiguous_cursor: rx.ranges.contiguous_upto(),
+            missing: Vec::new(),
+        };
+        match conn.stream_write(CTRL_STREAM_ID, msg.encode(), false) {
+            Ok(_) | Err(Error::Done) => {
+                info!(
+                    "[DGRAM] file_id={:016x} sent completion ack (contiguous_cursor={})",
+                    file_id, msg.contiguous_cursor
+                );
+            }
+            Err(e) => warn!("[DGRAM] file_id={file_id:016x} completion ack stream_write error: {e:?}"),
+        }
+    }
+
+    // Send a retransmit request for the gaps still outstanding in `file_id`,
+    // unless we've exhausted our NACK round budget or are too soon after the
+    // last one (unless `force`, used when the "last" datagram just arrived).
+    fn maybe_send_nack(&mut self, file_id: u64, conn: &mut Connection, force: bool) {
+        let Some(rx) = self.dgram_files.get_mut(&file_id) else {
+            return;
+        };
+        if rx.ranges.is_complete(rx.total) {
+            return;
+        }
+        if rx.nack_rounds_sent >= MAX_NACK_ROUNDS {
+            if !rx.gave_up {
+                rx.gave_up = true;
+                let missing = rx.ranges.missing_ranges(rx.total);
+                let missing_bytes: u64 = missing.iter().map(|(s, e)| e - s).sum();
+                warn!(
+                    "[DGRAM] file_id={:016x} giving up after {MAX_NACK_ROUNDS} NACK rounds: \
+                     {missing_bytes} bytes still missing across {} range(s), closing connection",
+                    file_id,
+                    missing.len()
+                );
+                // A client stuck waiting on a NACK that will never come
+                // (because we've stopped sending them) would otherwise
+                // hang forever on the close gate in
+                // `ClientHandler::try_send_more`. Fail closed instead of
+                // going silent.
+                let _ = conn.close(true, 0x01, b"datagram transfer incomplete: too much loss");
+            }
+            return;
+        }
+        if !force && rx.last_nack_check.elapsed() < NACK_INTERVAL {
+            return;
+        }
+        rx.last_nack_check = Instant::now();
+
+        let missing = rx.ranges.missing_ranges(rx.total);
+        let missing_bytes: u64 = missing.iter().map(|(s, e)| e - s).sum();
+        let entries: Vec<(u64, u32)> = missing
+            .iter()
+            .map(|(s, e)| (*s, (*e - *s) as u32))
+            .collect();
+
+        if conn.stream_bidi_new(CTRL_STREAM_ID, true).is_err() {
+            // already open, or stream machinery declined a second create; try
+            // to write to it regardless since it may simply already exist
+        }
+        let msg = NackMsg {
+            file_id,
+            contiguous_cursor: rx.ranges.contiguous_upto(),
+            missing: entries,
+        };
+        match conn.stream_write(CTRL_STREAM_ID, msg.encode(), false) {
+            Ok(_) | Err(Error::Done) => {
+                rx.nack_rounds_sent += 1;
+                info!(
+                    "[DGRAM] file_id={:016x} sent NACK round {}/{}: {} bytes missing across {} range(s)",
+                    file_id,
+                    rx.nack_rounds_sent,
+                    MAX_NACK_ROUNDS,
+                    missing_bytes,
+                    missing.len()
+                );
+                let now_ns = monotonic_ns();
+                self.csv_line(&format!("nack,{now_ns},{file_id:016x},{missing_bytes}"));
+            }
+            Err(e) => warn!("[DGRAM] file_id={file_id:016x} NACK stream_write error: {e:?}"),
         }
     }
 
@@ -332,12 +768,28 @@ impl ServerHandler {
                 if (payload.len() as u32) < hdr.len {
                     warn!("[DGRAM] payload shorter than header.len");
                 }
+                if hdr.is_parity() {
+                    self.handle_fec_parity(conn, &hdr, payload);
+                    continue;
+                }
+                if hdr
+                    .offset
+                    .checked_add(hdr.len as u64)
+                    .filter(|&end| end <= hdr.total_size)
+                    .is_none()
+                {
+                    warn!(
+                        "[DGRAM] bogus range file_id={:016x} off={} len={} total={}, dropping",
+                        hdr.file_id, hdr.offset, hdr.len, hdr.total_size
+                    );
+                    continue;
+                }
                 // 建立/获取文件并写入（短作用域，避免和 &mut self 冲突）
                 let write_ok = (|| -> std::io::Result<usize> {
                     let rx = self.get_or_create_dg_file(hdr.file_id, hdr.total_size)?;
-                    rx.f.seek(SeekFrom::Start(hdr.offset))?;
                     let to_write = std::cmp::min(payload.len(), hdr.len as usize);
-                    rx.f.write_all(&payload[..to_write])?;
+                    rx.write_at(hdr.offset, &payload[..to_write])?;
+                    rx.ranges.insert(hdr.offset, to_write as u64);
                     Ok(to_write)
                 })();
 
@@ -347,8 +799,53 @@ impl ServerHandler {
                             "recv,{},{:016x},{},{},datagram",
                             now_ns, hdr.file_id, hdr.offset, written
                         ));
+
+                        self.qlog_event(
+                            "datagram_received",
+                            &format!(
+                                "{{\"file_id\":\"{:016x}\",\"offset\":{},\"len\":{written}}}",
+                                hdr.file_id, hdr.offset
+                            ),
+                        );
+
+                        // Unlike `now_ns` above (process-local, for CSV/rate
+                        // timing), this has to line up with the client's own
+                        // wall clock: `hdr.send_ts_ns` is stamped with
+                        // `unix_epoch_ns()` on the client side precisely so
+                        // the two are comparable across processes/hosts.
+                        let now_wall_ns = unix_epoch_ns();
+                        let rate_sample = self.dgram_files.get_mut(&hdr.file_id).and_then(|rx| {
+                            rx.first_recv_ts_ns.get_or_insert(now_ns);
+                            rx.latency_sum_ns += now_wall_ns.saturating_sub(hdr.send_ts_ns) as u128;
+                            rx.latency_count += 1;
+                            rx.received_bytes += written as u64;
+                            rx.rate.sample(now_ns, rx.received_bytes)
+                        });
+                        if let Some((bytes_per_sec, app_limited)) = rate_sample {
+                            self.csv_line(&format!(
+                                "rate,{now_ns},{:016x},{bytes_per_sec:.2},{app_limited}",
+                                hdr.file_id
+                            ));
+                        }
+
+                        if hdr.fec_k > 0 {
+                            self.register_fec_data_shard(
+                                hdr.file_id,
+                                hdr.group_id,
+                                hdr.parity_idx,
+                                hdr.fec_k,
+                                hdr.offset,
+                                written,
+                                conn,
+                            );
+                        }
+
                         if hdr.is_last() {
-                            self.finish_if_complete_dg(hdr.file_id);
+                            self.finish_if_complete_dg(hdr.file_id, conn);
+                        } else {
+                            // Piggyback a periodic gap check on regular
+                            // traffic, alongside the `on_timeout` sweep.
+                            self.maybe_send_nack(hdr.file_id, conn, false);
                         }
                     }
                     Err(e) => error!("[DGRAM] write error: {e:?}"),
@@ -359,6 +856,167 @@ impl ServerHandler {
         }
     }
 
+    // Note that a data shard belonging to FEC coding block `group_id` has
+    // been written to disk, so a later parity arrival knows this shard
+    // doesn't need reconstructing.
+    fn register_fec_data_shard(
+        &mut self,
+        file_id: u64,
+        group_id: u32,
+        shard_idx: u8,
+        k: u8,
+        offset: u64,
+        len: usize,
+        conn: &mut Connection,
+    ) {
+        let Some(rx) = self.dgram_files.get_mut(&file_id) else {
+            return;
+        };
+        let state = rx.fec_blocks.entry(group_id).or_insert_with(|| FecBlockState {
+            k,
+            base_offset: offset.saturating_sub(shard_idx as u64 * len as u64),
+            shard_len: 0,
+            data_seen: vec![false; k as usize],
+            parity: Vec::new(),
+            resolved: false,
+        });
+        if (shard_idx as usize) < state.data_seen.len() {
+            state.data_seen[shard_idx as usize] = true;
+        }
+        state.shard_len = state.shard_len.max(len);
+        if shard_idx == 0 {
+            state.base_offset = offset;
+        }
+
+        // A data shard landing can be exactly what turns an
+        // until-now-unsolvable block solvable: e.g. reordering delivered
+        // the parity shard while more than `r` data shards were still
+        // outstanding (correctly deferred), and the remaining data shards
+        // have since arrived and closed the gap down to something the
+        // buffered parity can cover. Without this, that block's one truly
+        // lost shard is never recovered via FEC at all.
+        self.try_recover_fec(file_id, group_id, conn);
+    }
+
+    // A PARITY datagram never carries real file bytes directly — it's only
+    // useful for reconstructing whichever data shards of its block turn out
+    // to be missing, so buffer it and immediately try.
+    fn handle_fec_parity(&mut self, conn: &mut Connection, hdr: &DgHdr, payload: &[u8]) {
+        let shard = payload[..payload.len().min(hdr.len as usize)].to_vec();
+        let Ok(rx) = self.get_or_create_dg_file(hdr.file_id, hdr.total_size) else {
+            return;
+        };
+        let state = rx.fec_blocks.entry(hdr.group_id).or_insert_with(|| FecBlockState {
+            k: hdr.fec_k,
+            base_offset: hdr.offset,
+            shard_len: hdr.len as usize,
+            data_seen: vec![false; hdr.fec_k as usize],
+            parity: Vec::new(),
+            resolved: false,
+        });
+        state.base_offset = hdr.offset;
+        state.shard_len = state.shard_len.max(hdr.len as usize);
+        if !state.parity.iter().any(|(idx, _)| *idx == hdr.parity_idx as usize) {
+            state.parity.push((hdr.parity_idx as usize, shard));
+        }
+
+        self.try_recover_fec(hdr.file_id, hdr.group_id, conn);
+    }
+
+    // Reconstruct whichever data shards of `group_id` are still missing, if
+    // enough parity has arrived to solve for them, by re-reading the shards
+    // we do have off disk and running them back through the FEC decoder.
+    fn try_recover_fec(&mut self, file_id: u64, group_id: u32, conn: &mut Connection) {
+        let plan = {
+            let Some(rx) = self.dgram_files.get(&file_id) else {
+                return;
+            };
+            let Some(state) = rx.fec_blocks.get(&group_id) else {
+                return;
+            };
+            if state.resolved {
+                return;
+            }
+            let missing: Vec<usize> =
+                (0..state.k as usize).filter(|&i| !state.data_seen[i]).collect();
+            if missing.is_empty() || state.parity.len() < missing.len() {
+                return;
+            }
+            (
+                state.k,
+                state.base_offset,
+                state.shard_len,
+                missing,
+                state.parity.clone(),
+                rx.total,
+            )
+        };
+        let (k, base_offset, shard_len, missing, parity, total) = plan;
+        if shard_len == 0 {
+            return;
+        }
+
+        let mut known: Vec<Option<Vec<u8>>> = vec![None; k as usize];
+        {
+            let rx = self.dgram_files.get(&file_id).unwrap();
+            for i in 0..k as usize {
+                if missing.contains(&i) {
+                    continue;
+                }
+                let shard_off = base_offset + (i as u64) * shard_len as u64;
+                let real_len = shard_len.min(total.saturating_sub(shard_off) as usize);
+                if real_len == 0 {
+                    continue;
+                }
+                let mut buf = vec![0u8; shard_len];
+                if read_exact_at_cross(&rx.f, &mut buf[..real_len], shard_off).is_err() {
+                    // Shard we thought we had isn't readable yet (flushed
+                    // lazily); try again on the next parity arrival.
+                    return;
+                }
+                known[i] = Some(buf);
+            }
+        }
+
+        let Some(recovered) = crate::fec::rs_recover(&known, &parity, shard_len) else {
+            return;
+        };
+
+        for (slot, &shard_idx) in missing.iter().enumerate() {
+            let shard_off = base_offset + (shard_idx as u64) * shard_len as u64;
+            let real_len = shard_len.min(total.saturating_sub(shard_off) as usize);
+            if real_len == 0 {
+                continue;
+            }
+            let bytes = &recovered[slot][..real_len];
+            let rx = self.dgram_files.get_mut(&file_id).unwrap();
+            if let Err(e) = rx.write_at(shard_off, bytes) {
+                error!("[FEC] recovered-shard write error: {e:?}");
+                continue;
+            }
+            rx.ranges.insert(shard_off, real_len as u64);
+            rx.fec_blocks.get_mut(&group_id).unwrap().data_seen[shard_idx] = true;
+            info!(
+                "[FEC] file_id={:016x} group={group_id} recovered shard {shard_idx} ({real_len} bytes) from parity",
+                file_id
+            );
+            self.qlog_event(
+                "fec_recovered",
+                &format!(
+                    "{{\"file_id\":\"{file_id:016x}\",\"group_id\":{group_id},\"shard\":{shard_idx},\"len\":{real_len}}}"
+                ),
+            );
+        }
+        if let Some(state) = self
+            .dgram_files
+            .get_mut(&file_id)
+            .and_then(|rx| rx.fec_blocks.get_mut(&group_id))
+        {
+            state.resolved = true;
+        }
+        self.finish_if_complete_dg(file_id, conn);
+    }
+
     fn handle_stream_readable(&mut self, conn: &mut Connection, stream_id: u64) {
         // 如未创建文件，先创建
         if !self.stream_files.contains_key(&stream_id) {
@@ -373,6 +1031,15 @@ impl ServerHandler {
                             f,
                             total: 0,
                             received_bytes: 0,
+                            ranges: RangeTracker::new(),
+                            nack_rounds_sent: 0,
+                            gave_up: false,
+                            last_nack_check: Instant::now(),
+                            rate: RateEstimator::new(),
+                            first_recv_ts_ns: None,
+                            latency_sum_ns: 0,
+                            latency_count: 0,
+                            fec_blocks: HashMap::new(),
                         },
                     );
                     info!("[STREAM] create file for stream {} -> {}", stream_id, path.display());
@@ -388,31 +1055,54 @@ impl ServerHandler {
             match conn.stream_read(stream_id, &mut self.buf) {
                 Ok((n, fin)) => {
                     if n > 0 {
+                        let now = monotonic_ns();
                         // 缩小 rx 可变借用作用域，避免与 self.csv_line 冲突
-                        {
+                        let rate_sample = {
                             let rx = self.stream_files.get_mut(&stream_id).unwrap();
                             if let Err(e) = rx.f.write_all(&self.buf[..n]) {
                                 error!("[STREAM] write error: {e:?}");
                                 break;
                             }
+                            rx.first_recv_ts_ns.get_or_insert(now);
                             rx.received_bytes += n as u64;
-                        } // 这里结束 rx 的可变借用
+                            rx.rate.sample(now, rx.received_bytes)
+                        }; // 这里结束 rx 的可变借用
 
-                        let now = monotonic_ns();
                         self.csv_line(&format!("recv,{now},0,{n},stream"));
+                        if let Some((bytes_per_sec, app_limited)) = rate_sample {
+                            self.csv_line(&format!(
+                                "rate,{now},{stream_id:016x},{bytes_per_sec:.2},{app_limited}"
+                            ));
+                        }
                     }
 
                     if fin {
                         // 再次短借用 flush，然后打印日志
-                        let (total, path) = {
+                        let (total, path, duration_s, mean, peak) = {
                             let rx = self.stream_files.get_mut(&stream_id).unwrap();
                             let _ = rx.f.flush();
-                            (rx.received_bytes, rx.path.clone())
+                            let now = monotonic_ns();
+                            let duration_s = (now.saturating_sub(rx.first_recv_ts_ns.unwrap_or(now)))
+                                as f64
+                                / 1e9;
+                            (
+                                rx.received_bytes,
+                                rx.path.clone(),
+                                duration_s,
+                                rx.rate.mean_bytes_per_sec(),
+                                rx.rate.peak_bytes_per_sec,
+                            )
                         };
                         info!(
-                            "[STREAM] {stream_id} finished: {total} bytes -> {}",
+                            "[STREAM] {stream_id} finished: {total} bytes -> {} ({duration_s:.3}s, mean={mean:.0}B/s, peak={peak:.0}B/s)",
                             path.display()
                         );
+                        self.qlog_event(
+                            "stream_completed",
+                            &format!(
+                                "{{\"stream_id\":{stream_id},\"total_bytes\":{total},\"duration_s\":{duration_s:.3}}}"
+                            ),
+                        );
                         break;
                     }
                 }
@@ -474,15 +1164,41 @@ impl TransportHandler for ServerHandler {
     }
     fn on_datagram_acked(&mut self, conn: &mut Connection) {
         debug!("{} dgram acked", conn.trace_id());
+        self.qlog_event(
+            "datagram_acked",
+            &format!("{{\"trace_id\":\"{}\"}}", conn.trace_id()),
+        );
     }
     fn on_datagram_drop(&mut self, conn: &mut Connection) {
         debug!("{} dgram drop", conn.trace_id());
+        self.qlog_event(
+            "datagram_dropped",
+            &format!("{{\"trace_id\":\"{}\"}}", conn.trace_id()),
+        );
     }
     fn on_datagram_longtime(&mut self, conn: &mut Connection) {
         debug!("{} dgram longtime", conn.trace_id());
     }
     fn on_datagram_losted(&mut self, conn: &mut Connection) {
         debug!("{} dgram losted", conn.trace_id());
+        self.qlog_event(
+            "datagram_lost",
+            &format!("{{\"trace_id\":\"{}\"}}", conn.trace_id()),
+        );
+    }
+
+    // Sweep every in-progress datagram transfer for outstanding gaps on
+    // each connection timer tick, not only when a `last`-flagged datagram
+    // happens to arrive. Without this, losing the `last` datagram (or any
+    // tail chunk that never triggers another arrival) left a transfer
+    // stalled with no NACK round ever sent — `maybe_send_nack`'s own
+    // `NACK_INTERVAL`/`MAX_NACK_ROUNDS` gating still applies, so this is
+    // safe to call unconditionally.
+    fn on_timeout(&mut self, conn: &mut Connection) {
+        let file_ids: Vec<u64> = self.dgram_files.keys().copied().collect();
+        for file_id in file_ids {
+            self.maybe_send_nack(file_id, conn, false);
+        }
     }
 }
 
@@ -493,11 +1209,37 @@ fn monotonic_ns() -> u64 {
     START.with(|s| s.elapsed().as_nanos() as u64)
 }
 
-fn main() -> Result<()> {
-    let opt = ServerOpt::parse();
-    env_logger::builder().filter_level(opt.log_level).init();
+// Wall-clock nanos since the Unix epoch. `monotonic_ns()` above is fine for
+// timing things within this one process (CSV/qlog timestamps, rate
+// sampling), but it zeroes against a lazily-initialized per-process
+// `Instant` with no relationship to the client's own `Instant`. Cross-process
+// measurements — specifically, one-way latency against the client's
+// `hdr.send_ts_ns` — need a clock both sides actually share a reference
+// point for, which a raw `Instant` can never give them.
+fn unix_epoch_ns() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// Run the `recv` subcommand: listen on `opt.listen` and accept incoming
+/// file transfers until interrupted.
+pub fn run(opt: ServerOpt) -> Result<()> {
+    env_logger::builder().filter_level(opt.log_level).try_init().ok();
 
     let mut server = Server::new(&opt)?;
+    run_loop(&mut server)
+}
+
+/// Drive an already-constructed `Server` (e.g. one bound to an ephemeral
+/// port for a test) through its accept loop.
+pub fn run_server(mut server: Server) -> Result<()> {
+    run_loop(&mut server)
+}
+
+fn run_loop(server: &mut Server) -> Result<()> {
     let mut events = mio::Events::with_capacity(1024);
 
     loop {
@@ -515,4 +1257,55 @@ fn main() -> Result<()> {
     }
 }
 
-pub(crate) mod qskt;
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::RangeTracker;
+
+    #[test]
+    fn empty_tracker_is_missing_everything() {
+        let rt = RangeTracker::new();
+        assert!(!rt.is_complete(100));
+        assert_eq!(rt.contiguous_upto(), 0);
+        assert_eq!(rt.missing_ranges(100), vec![(0, 100)]);
+    }
+
+    #[test]
+    fn insert_merges_overlapping_and_abutting_ranges() {
+        let mut rt = RangeTracker::new();
+        rt.insert(0, 10); // [0, 10)
+        rt.insert(20, 10); // [20, 30), separate
+        rt.insert(10, 10); // [10, 20), abuts both -> merges into [0, 30)
+        assert_eq!(rt.contiguous_upto(), 30);
+        assert_eq!(rt.missing_ranges(40), vec![(30, 40)]);
+        assert!(!rt.is_complete(40));
+
+        rt.insert(30, 10); // [30, 40) -> merges into [0, 40)
+        assert!(rt.is_complete(40));
+        assert_eq!(rt.missing_ranges(40), Vec::new());
+    }
+
+    #[test]
+    fn insert_ignores_zero_length_range() {
+        let mut rt = RangeTracker::new();
+        rt.insert(5, 0);
+        assert_eq!(rt.contiguous_upto(), 0);
+        assert_eq!(rt.missing_ranges(10), vec![(0, 10)]);
+    }
+
+    #[test]
+    fn insert_handles_out_of_order_overlapping_chunks() {
+        let mut rt = RangeTracker::new();
+        rt.insert(50, 50); // [50, 100)
+        rt.insert(0, 60); // [0, 60), overlaps the first -> merges into [0, 100)
+        assert!(rt.is_complete(100));
+    }
+
+    #[test]
+    fn missing_ranges_reports_every_gap() {
+        let mut rt = RangeTracker::new();
+        rt.insert(10, 10); // [10, 20)
+        rt.insert(40, 10); // [40, 50)
+        assert_eq!(rt.missing_ranges(50), vec![(0, 10), (20, 40)]);
+        assert_eq!(rt.contiguous_upto(), 0); // nothing contiguous from 0 yet
+    }
+}
\ No newline at end of file
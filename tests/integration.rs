@@ -0,0 +1,211 @@
+// End-to-end test: start the `recv` side on an ephemeral port, run `send`
+// against it for both transfer modes, and check the received file matches
+// the source file byte-for-byte (and that the completion log actually
+// fires — see `CapturingLogger`).
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::{Mutex, Once};
+use std::thread;
+use std::time::Duration;
+
+use tquic_datagram_example::{client, server};
+
+// `client::run`/`server::run` each try to install an `env_logger` via
+// `try_init().ok()`, so whichever logger wins the race to `log::set_logger`
+// receives every subsequent `log::info!` call process-wide, including the
+// `[DGRAM]`/`[STREAM]` completion lines. Installing this one first lets the
+// test assert on those lines directly instead of having to scrape stderr.
+struct CapturingLogger {
+    lines: Mutex<Vec<String>>,
+}
+impl log::Log for CapturingLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Info
+    }
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            self.lines.lock().unwrap().push(format!("{}", record.args()));
+        }
+    }
+    fn flush(&self) {}
+}
+static LOGGER: CapturingLogger = CapturingLogger {
+    lines: Mutex::new(Vec::new()),
+};
+
+fn init_logger() {
+    static ONCE: Once = Once::new();
+    ONCE.call_once(|| {
+        log::set_logger(&LOGGER).expect("install test logger");
+        log::set_max_level(log::LevelFilter::Info);
+    });
+}
+
+// Completion lines include the receiver's output path (see
+// `finish_if_complete_dg`/`on_stream_readable`), which is unique per test's
+// temp dir — enough to pick this test's line out of the shared logger even
+// though tests run concurrently in the same process.
+fn assert_completion_logged(marker: &str, received: &std::path::Path) {
+    let path_str = received.to_string_lossy().into_owned();
+    let lines = LOGGER.lines.lock().unwrap();
+    assert!(
+        lines.iter().any(|l| l.contains(marker) && l.contains(&path_str)),
+        "expected a completion log line containing {marker:?} and {path_str:?}, got: {lines:#?}"
+    );
+}
+
+fn cert_path() -> String {
+    format!("{}/tests/fixtures/cert.crt", env!("CARGO_MANIFEST_DIR"))
+}
+
+fn key_path() -> String {
+    format!("{}/tests/fixtures/cert.key", env!("CARGO_MANIFEST_DIR"))
+}
+
+fn hash_file(path: &std::path::Path) -> u64 {
+    let data = fs::read(path).expect("read output file");
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn start_server(out_dir: &std::path::Path) -> SocketAddr {
+    let opt = server::ServerOpt {
+        cert_file: cert_path(),
+        key_file: key_path(),
+        // Info, not Warn: the completion logs the tests assert on are
+        // emitted at Info. The effective level actually comes from
+        // whichever logger wins the `CapturingLogger` race above, but
+        // this keeps the option's stated intent honest either way.
+        log_level: log::LevelFilter::Info,
+        listen: "127.0.0.1:0".parse().unwrap(),
+        idle_timeout: 5_000_000,
+        keylog_file: None,
+        qlog_file: None,
+        max_datagram_frame_size: 65535,
+        send_timeout: 5_000_000,
+        priority: 31,
+        datagram_event_mask: 31,
+        out_dir: out_dir.to_string_lossy().into_owned(),
+        flush_every: 200,
+        csv_recv: None,
+        cca: None,
+    };
+
+    let srv = server::Server::new(&opt).expect("start server");
+    let addr = srv.local_addr();
+    thread::spawn(move || {
+        let _ = server::run_server(srv);
+    });
+    // give the listener a moment to be scheduled before the client dials.
+    thread::sleep(Duration::from_millis(50));
+    addr
+}
+
+fn run_transfer(mode: &str, addr: SocketAddr, in_file: &std::path::Path) {
+    let opt = client::ClientOpt {
+        // See the matching comment in `start_server`.
+        log_level: log::LevelFilter::Info,
+        connect_to: addr,
+        idle_timeout: 5_000_000,
+        session_file: None,
+        keylog_file: None,
+        qlog_file: None,
+        max_datagram_frame_size: 65535,
+        send_timeout: 500_000,
+        priority: 31,
+        datagram_event_mask: 31,
+        mode: mode.parse().unwrap(),
+        in_file: in_file.to_path_buf(),
+        chunk_bytes: 1200,
+        rate_mbps: 50.0,
+        csv_send: None,
+        cca: None,
+        enable_0rtt: false,
+        cwnd_gain: 2.0,
+        reliable: false,
+        nack_interval_ms: 100,
+        fec_k: 0,
+        fec_r: 1,
+        fec_mode: client::FecMode::Xor,
+        runtime: client::RuntimeMode::Mio,
+        migrate_after: None,
+    };
+    client::run(opt).expect("client run");
+}
+
+#[test]
+fn datagram_transfer_round_trips_file() {
+    init_logger();
+    let tmp = tempfile_dir("dg");
+    let in_file = tmp.join("input.bin");
+    fs::write(&in_file, random_bytes(256 * 1024)).unwrap();
+
+    let out_dir = tmp.join("out");
+    fs::create_dir_all(&out_dir).unwrap();
+    let addr = start_server(&out_dir);
+
+    run_transfer("datagram", addr, &in_file);
+    thread::sleep(Duration::from_millis(200));
+
+    let received = find_received_file(&out_dir, "dgram_");
+    assert_eq!(hash_file(&in_file), hash_file(&received));
+    assert_completion_logged("completed:", &received);
+}
+
+#[test]
+fn stream_transfer_round_trips_file() {
+    init_logger();
+    let tmp = tempfile_dir("stream");
+    let in_file = tmp.join("input.bin");
+    fs::write(&in_file, random_bytes(256 * 1024)).unwrap();
+
+    let out_dir = tmp.join("out");
+    fs::create_dir_all(&out_dir).unwrap();
+    let addr = start_server(&out_dir);
+
+    run_transfer("stream", addr, &in_file);
+    thread::sleep(Duration::from_millis(200));
+
+    let received = find_received_file(&out_dir, "stream_");
+    assert_eq!(hash_file(&in_file), hash_file(&received));
+    assert_completion_logged("finished:", &received);
+}
+
+fn find_received_file(out_dir: &std::path::Path, prefix: &str) -> std::path::PathBuf {
+    fs::read_dir(out_dir)
+        .unwrap()
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .find(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .map(|n| n.starts_with(prefix))
+                .unwrap_or(false)
+        })
+        .expect("no received file found")
+}
+
+fn tempfile_dir(tag: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "tquic-datagram-example-test-{tag}-{:?}",
+        std::thread::current().id()
+    ));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn random_bytes(n: usize) -> Vec<u8> {
+    // No external RNG dependency: a simple xorshift is enough entropy to
+    // make truncation/corruption bugs visible in the hash comparison.
+    let mut state: u64 = 0x2545F4914F6CDD1D;
+    let mut out = Vec::with_capacity(n);
+    for _ in 0..n {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        out.push((state & 0xff) as u8);
+    }
+    out
+}